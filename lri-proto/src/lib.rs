@@ -1,4 +1,4 @@
 mod proto;
 
 pub use proto::*;
-pub use protobuf::Message;
+pub use protobuf::{EnumOrUnknown, Error, Message};
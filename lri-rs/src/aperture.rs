@@ -0,0 +1,203 @@
+//! Shallow depth of field, like Lumen rendered, from a depth map.
+//!
+//! Every pixel is blurred into a disc as big as the circle of confusion a real
+//! lens with the [Aperture] would have had for its depth. The lens is taken to
+//! be a 35mm equivalent one, so the blur is worked out on a 36mm wide frame and
+//! scaled to the image.
+
+use crate::{FloatImage, LriFile};
+
+/// Width of a 35mm frame, in millimeters
+const FRAME_WIDTH: f32 = 36.0;
+
+/// The lens to render the depth of field of. Nothing here is fixed at capture,
+/// so change it to taste.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aperture {
+	pub f_number: f32,
+	/// 35mm equivalent, in millimeters
+	pub focal_length: f32,
+	/// The distance that's in focus, in the units of the depth map
+	pub focus_distance: f32,
+	/// The most a pixel is blurred, as a radius in pixels. Rendering takes
+	/// time with the square of this.
+	pub max_radius: usize,
+}
+
+impl Aperture {
+	/// Radius of the circle of confusion in pixels of an image `width` pixels
+	/// wide for something `depth` away. We assume depth is in millimeters.
+	pub fn blur_radius(&self, depth: f32, width: usize) -> f32 {
+		let f = self.focal_length;
+		let focus = self.focus_distance.max(f * 1.001);
+
+		// Diameter on the 35mm frame. Things at infinity blur as much as
+		// anything can behind the focus distance.
+		let scale = f * f / (self.f_number * (focus - f));
+		let diameter = if depth.is_infinite() {
+			scale
+		} else {
+			scale * (depth - focus).abs() / depth.max(f32::EPSILON)
+		};
+
+		(diameter / FRAME_WIDTH * width as f32 / 2.0).min(self.max_radius as f32)
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// The aperture picked on the camera, focused where the reference camera
+	/// was, for rendering `depth` from [LriFile::depth_map]. If we don't know
+	/// where it was focused we focus on the middle of `depth`. We think
+	/// [LriFile::focal_length] is 35mm equivalent and use it if it's there,
+	/// otherwise the focal length of the reference camera's group.
+	///
+	/// `max_radius` is 16. Returns `None` if there's no f-number or reference
+	/// camera.
+	pub fn aperture(&self, depth: &FloatImage) -> Option<Aperture> {
+		let reference = self.reference_image()?;
+
+		let focal_length = self
+			.focal_length
+			.map(|f| f as f32)
+			.unwrap_or_else(|| reference.camera.group().focal_length());
+
+		let focus_distance = self
+			.focus_distance(reference)
+			.or_else(|| focus_at(depth, depth.width / 2, depth.height / 2))?;
+
+		Some(Aperture {
+			f_number: self.f_number?,
+			focal_length,
+			focus_distance,
+			max_radius: 16,
+		})
+	}
+}
+
+/// The median depth in the 9x9 pixels around `x`, `y`, for focusing on a
+/// point. `None` if none of them have a depth.
+pub fn focus_at(depth: &FloatImage, x: usize, y: usize) -> Option<f32> {
+	const RADIUS: usize = 4;
+
+	let mut depths = vec![];
+	for y in y.saturating_sub(RADIUS)..(y + RADIUS + 1).min(depth.height) {
+		for x in x.saturating_sub(RADIUS)..(x + RADIUS + 1).min(depth.width) {
+			let d = depth.get(x, y, 0);
+			if d > 0.0 {
+				depths.push(d);
+			}
+		}
+	}
+
+	depths.sort_by(f32::total_cmp);
+	depths.get(depths.len() / 2).copied()
+}
+
+/// Render `image` as if taken with `aperture`. `depth` is one channel and
+/// aligned to `image`, with 0.0 where the depth isn't known. Those pixels take
+/// the furthest depth around them, as they're usually background with too
+/// little texture to match.
+///
+/// Each pixel is spread over its circle of confusion, but something behind a
+/// pixel can't spread over it more than the pixel itself is blurred, so
+/// backgrounds don't bleed onto what's in focus in front of them.
+///
+/// # Panics
+/// If `depth` isn't the same size as `image`
+pub fn render(image: &FloatImage, depth: &FloatImage, aperture: &Aperture) -> FloatImage {
+	assert_eq!((image.width, image.height), (depth.width, depth.height));
+	let (width, height, channels) = (image.width, image.height, image.channels);
+
+	let depth = fill_holes(depth);
+	let radii: Vec<f32> = depth
+		.iter()
+		.map(|d| aperture.blur_radius(*d, width).max(0.5))
+		.collect();
+
+	let reach = aperture.max_radius as isize;
+	let mut out = FloatImage::new(width, height, channels);
+	let mut sum = vec![0.0; channels];
+
+	for y in 0..height {
+		for x in 0..width {
+			let here = y * width + x;
+			sum.iter_mut().for_each(|s| *s = 0.0);
+			let mut total = 0.0;
+
+			for dy in -reach..=reach {
+				let Some(sy) = y.checked_add_signed(dy).filter(|sy| *sy < height) else {
+					continue;
+				};
+
+				for dx in -reach..=reach {
+					let Some(sx) = x.checked_add_signed(dx).filter(|sx| *sx < width) else {
+						continue;
+					};
+
+					let there = sy * width + sx;
+					let radius = if depth[there] > depth[here] {
+						radii[there].min(radii[here])
+					} else {
+						radii[there]
+					};
+
+					if ((dx * dx + dy * dy) as f32) > radius * radius {
+						continue;
+					}
+
+					// Spreading a pixel out doesn't make it any brighter
+					let weight = 1.0 / (radius * radius);
+					for (c, s) in sum.iter_mut().enumerate() {
+						*s += image.get(sx, sy, c) * weight;
+					}
+					total += weight;
+				}
+			}
+
+			for (c, s) in sum.iter().enumerate() {
+				out.set(x, y, c, s / total);
+			}
+		}
+	}
+
+	out
+}
+
+/// Give every pixel without a depth the furthest depth of its neighbours,
+/// over and over until they all have one. Infinite if nothing has a depth.
+fn fill_holes(depth: &FloatImage) -> Vec<f32> {
+	let (width, height) = (depth.width, depth.height);
+	let mut filled: Vec<f32> = depth
+		.data
+		.iter()
+		.map(|d| if *d > 0.0 { *d } else { f32::NAN })
+		.collect();
+
+	if filled.iter().all(|d| d.is_nan()) {
+		return vec![f32::INFINITY; filled.len()];
+	}
+
+	while filled.iter().any(|d| d.is_nan()) {
+		let last = filled.clone();
+		for y in 0..height {
+			for x in 0..width {
+				if !last[y * width + x].is_nan() {
+					continue;
+				}
+
+				let rows = y.saturating_sub(1)..(y + 2).min(height);
+				let furthest = rows
+					.flat_map(|y| (x.saturating_sub(1)..(x + 2).min(width)).map(move |x| (x, y)))
+					.map(|(x, y)| last[y * width + x])
+					.filter(|d| !d.is_nan())
+					.max_by(f32::total_cmp);
+
+				if let Some(d) = furthest {
+					filled[y * width + x] = d;
+				}
+			}
+		}
+	}
+
+	filled
+}
@@ -0,0 +1,289 @@
+//! The BayerJPEG header and, with the `bayer-jpeg` feature, decoding of the
+//! images themselves.
+//!
+//! See `bayer_jpeg.md` at the root of the repository for what we know of the
+//! format.
+
+#[cfg(feature = "bayer-jpeg")]
+use std::fmt;
+
+#[cfg(feature = "bayer-jpeg")]
+use crate::{CameraId, RawData, RawImage};
+
+/// The fixed size header at the start of every BayerJPEG
+#[derive(Copy, Clone, Debug)]
+pub struct BayerJpegHeader<'img> {
+	/// 0 for colour, 1 for monochrome
+	pub format: u32,
+	/// Length of each of the JPEGs. Monochrome images only use the first.
+	pub jpeg_lengths: [u32; 4],
+	/// Everything after the lengths. We don't know what's in here yet, so
+	/// there are a few accessors to look at it as different types.
+	pub unknown: &'img [u8; BayerJpegHeader::UNKNOWN_LENGTH],
+}
+
+impl<'img> BayerJpegHeader<'img> {
+	/// Length of the entire header, magic included
+	pub const LENGTH: usize = 1576;
+	/// Length of the part after the JPEG lengths
+	pub const UNKNOWN_LENGTH: usize = Self::LENGTH - 24;
+
+	/// Parse the header from the start of `data`. Returns `None` if `data`
+	/// is too short or doesn't start with the "BJPG" magic.
+	pub fn parse(data: &'img [u8]) -> Option<Self> {
+		let data = data.get(..Self::LENGTH)?;
+		if &data[0..4] != b"BJPG" {
+			return None;
+		}
+
+		let read_u32 = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+
+		Some(Self {
+			format: read_u32(4),
+			jpeg_lengths: [read_u32(8), read_u32(12), read_u32(16), read_u32(20)],
+			unknown: data[24..].try_into().unwrap(),
+		})
+	}
+
+	/// The unknown bytes as little endian u32
+	pub fn unknown_u32(&self) -> [u32; BayerJpegHeader::UNKNOWN_LENGTH / 4] {
+		std::array::from_fn(|i| {
+			u32::from_le_bytes(self.unknown[i * 4..i * 4 + 4].try_into().unwrap())
+		})
+	}
+
+	/// The unknown bytes as little endian f32
+	pub fn unknown_f32(&self) -> [f32; BayerJpegHeader::UNKNOWN_LENGTH / 4] {
+		self.unknown_u32().map(f32::from_bits)
+	}
+
+	/// The unknown bytes as little endian u16
+	pub fn unknown_u16(&self) -> [u16; BayerJpegHeader::UNKNOWN_LENGTH / 2] {
+		std::array::from_fn(|i| {
+			u16::from_le_bytes(self.unknown[i * 2..i * 2 + 2].try_into().unwrap())
+		})
+	}
+}
+
+#[cfg(feature = "bayer-jpeg")]
+impl<'img> RawImage<'img> {
+	/// Decode a BayerJPEG into an 8-bit mosaic, one sample per pixel, in the
+	/// same order [RawImage::unpack] gives you. Returns `Ok(None)` for images
+	/// that aren't BayerJPEG.
+	pub fn decode_bayer_jpeg(&self) -> Result<Option<Vec<u8>>, BayerJpegError> {
+		let mut out = vec![0; self.width * self.height];
+		Ok(self.decode_bayer_jpeg_into(&mut out)?.then_some(out))
+	}
+
+	/// Like [RawImage::decode_bayer_jpeg] but into a buffer you provide.
+	/// Returns `Ok(false)`, leaving `out` untouched, if the image isn't
+	/// BayerJPEG.
+	///
+	/// # Panics
+	/// If `out` is shorter than `width * height`.
+	pub fn decode_bayer_jpeg_into(&self, out: &mut [u8]) -> Result<bool, BayerJpegError> {
+		let RawData::BayerJpeg {
+			header,
+			jpeg0,
+			jpeg1,
+			jpeg2,
+			jpeg3,
+		} = self.data
+		else {
+			return Ok(false);
+		};
+
+		let (width, height) = (self.width, self.height);
+		assert!(out.len() >= width * height);
+
+		match header.format {
+			// Each JPEG holds one position of the 2x2 CFA at quarter resolution
+			0 => {
+				let (half_width, half_height) = (width / 2, height / 2);
+				let mut plane = vec![0; half_width * half_height];
+
+				for (idx, jpeg) in [jpeg0, jpeg1, jpeg2, jpeg3].into_iter().enumerate() {
+					self.decode_jpeg(idx, jpeg, &mut plane)?;
+					place_plane(&plane, idx, width, out);
+				}
+			}
+			// Monochrome, it's the entire image in the first JPEG
+			1 => self.decode_jpeg(0, jpeg0, &mut out[..width * height])?,
+			// block.rs refuses any other format
+			_ => unreachable!(),
+		}
+
+		Ok(true)
+	}
+
+	/// Decode a BayerJPEG and rebuild sensor values from it, scaled to the
+	/// bit depth of the sensor (10-bit if we don't know it). Same order as
+	/// [RawImage::unpack], so the two can be compared directly. Returns
+	/// `Ok(None)` for images that aren't BayerJPEG.
+	pub fn reconstruct_bayer_jpeg(
+		&self,
+		mode: BayerJpegReconstruction,
+	) -> Result<Option<Vec<u16>>, BayerJpegError> {
+		let RawData::BayerJpeg { header, .. } = self.data else {
+			return Ok(None);
+		};
+		let Some(mosaic) = self.decode_bayer_jpeg()? else {
+			return Ok(None);
+		};
+
+		let bits = self.sensor.bit_depth().unwrap_or(10);
+
+		// Monochrome only has the one JPEG, nothing to sum
+		let mode = match header.format {
+			1 => BayerJpegReconstruction::PerChannel,
+			_ => mode,
+		};
+
+		Ok(Some(reconstruct(
+			&mosaic,
+			self.width,
+			self.height,
+			bits,
+			mode,
+		)))
+	}
+
+	fn decode_jpeg(&self, jpeg: usize, data: &[u8], out: &mut [u8]) -> Result<(), BayerJpegError> {
+		zune_jpeg::JpegDecoder::new(data)
+			.decode_into(out)
+			.map_err(|e| BayerJpegError {
+				camera: self.camera,
+				jpeg,
+				reason: format!("{e:?}"),
+			})
+	}
+}
+
+/// Copy a quarter resolution `plane` into the `idx`th position of the 2x2
+/// cells of `out`, a mosaic `width` wide. 0 is the top-left, 1 the top-right,
+/// 2 the bottom-left, and 3 the bottom-right.
+#[cfg(feature = "bayer-jpeg")]
+fn place_plane(plane: &[u8], idx: usize, width: usize, out: &mut [u8]) {
+	let (off_x, off_y) = (idx % 2, idx / 2);
+	for (y, row) in plane.chunks_exact(width / 2).enumerate() {
+		let out_row = &mut out[(y * 2 + off_y) * width..];
+
+		for (x, px) in row.iter().enumerate() {
+			out_row[x * 2 + off_x] = *px;
+		}
+	}
+}
+
+/// Rebuild `bits` deep samples from an 8-bit `mosaic` the way `mode` says to
+#[cfg(feature = "bayer-jpeg")]
+fn reconstruct(
+	mosaic: &[u8],
+	width: usize,
+	height: usize,
+	bits: usize,
+	mode: BayerJpegReconstruction,
+) -> Vec<u16> {
+	if mode == BayerJpegReconstruction::PerChannel {
+		let shift = bits.saturating_sub(8);
+		return mosaic.iter().map(|p| (*p as u16) << shift).collect();
+	}
+
+	// Four 8-bit values sum to a 10-bit one
+	let shift = bits.saturating_sub(10);
+	let mut out = vec![0; width * height];
+
+	for y in (0..height - height % 2).step_by(2) {
+		for x in (0..width - width % 2).step_by(2) {
+			let cell = [
+				y * width + x,
+				y * width + x + 1,
+				(y + 1) * width + x,
+				(y + 1) * width + x + 1,
+			];
+
+			let sum: u16 = cell.iter().map(|idx| mosaic[*idx] as u16).sum();
+			cell.iter().for_each(|idx| out[*idx] = sum << shift);
+		}
+	}
+
+	out
+}
+
+/// How [RawImage::reconstruct_bayer_jpeg] should get sensor values back out
+/// of the 8-bit JPEGs. We don't know which of these the L16 does yet, so
+/// compare them against a packed image of the same scene.
+#[cfg(feature = "bayer-jpeg")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BayerJpegReconstruction {
+	/// Each JPEG is one position of the CFA, so each sample is shifted up
+	/// from 8-bit to the bit depth of the sensor.
+	PerChannel,
+	/// The signal was divided across the four JPEGs and they have to be summed
+	/// back together. Every pixel of a 2x2 cell gets the sum of the four, so
+	/// if this is right the cells aren't a CFA at all.
+	Summed,
+}
+
+/// One of the JPEGs in a BayerJPEG failed to decode
+#[cfg(feature = "bayer-jpeg")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BayerJpegError {
+	/// The camera the image came from
+	pub camera: CameraId,
+	/// Which of the, up to four, JPEGs it was
+	pub jpeg: usize,
+	/// What the JPEG decoder had to say about it
+	pub reason: String,
+}
+
+#[cfg(feature = "bayer-jpeg")]
+impl fmt::Display for BayerJpegError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"failed to decode jpeg {} of the BayerJPEG from {}: {}",
+			self.jpeg, self.camera, self.reason
+		)
+	}
+}
+
+#[cfg(feature = "bayer-jpeg")]
+impl std::error::Error for BayerJpegError {}
+
+#[cfg(all(test, feature = "bayer-jpeg"))]
+mod test {
+	use super::*;
+
+	/// A 4x2 mosaic out of four 2x1 planes
+	fn mosaic() -> Vec<u8> {
+		let planes: [&[u8]; 4] = [&[10, 20], &[30, 40], &[50, 60], &[70, 80]];
+
+		let mut mosaic = vec![0; 8];
+		for (idx, plane) in planes.into_iter().enumerate() {
+			place_plane(plane, idx, 4, &mut mosaic);
+		}
+
+		mosaic
+	}
+
+	#[test]
+	fn planes_interleave() {
+		assert_eq!(mosaic(), [10, 30, 20, 40, 50, 70, 60, 80]);
+	}
+
+	#[test]
+	fn per_channel() {
+		let out = reconstruct(&mosaic(), 4, 2, 10, BayerJpegReconstruction::PerChannel);
+		assert_eq!(out, [40, 120, 80, 160, 200, 280, 240, 320]);
+	}
+
+	#[test]
+	fn summed() {
+		let out = reconstruct(&mosaic(), 4, 2, 10, BayerJpegReconstruction::Summed);
+		assert_eq!(out, [160, 160, 200, 200, 160, 160, 200, 200]);
+
+		// A 12-bit sensor gets the 10-bit sum shifted up
+		let out = reconstruct(&mosaic(), 4, 2, 12, BayerJpegReconstruction::Summed);
+		assert_eq!(out, [640, 640, 800, 800, 640, 640, 800, 800]);
+	}
+}
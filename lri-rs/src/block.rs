@@ -1,45 +1,100 @@
 use std::time::Duration;
 
 use lri_proto::{
-	gps_data::GPSData, lightheader::LightHeader, matrix3x3f::Matrix3x3F,
+	gps_data::GPSData, lightheader::LightHeader, matrix3x3f::Matrix3x3F, matrix4x4f::Matrix4x4F,
 	view_preferences::ViewPreferences, Message as PbMessage,
 };
 
 use crate::{
-	AwbGain, AwbMode, CameraId, CameraInfo, ColorInfo, DataFormat, HdrMode, RawData, RawImage,
-	SceneMode, SensorModel,
+	unpack, AwbGain, AwbMode, BayerJpegHeader, CameraId, CameraInfo, ColorInfo, CrosstalkGrid,
+	DataFormat, DateTime, DeadPixelMap, DeviceId, GpsInfo, HdrMode, HotPixelMeasurement, ImageId,
+	LriError, MirrorVignetting, ModuleCalibration, ModuleCapture, RawData, RawImage, SceneMode,
+	SensorModel, SensorProfile, Vignetting, VignettingGrid,
 };
 
 pub(crate) struct Block<'lri> {
+	/// Which block this is in the file, counting from zero.
+	pub index: usize,
+	/// Where in the file this block starts
+	pub offset: usize,
 	pub header: Header,
 	/// This includes the 32 bytes that make up the header.
 	pub data: &'lri [u8],
 }
 
 impl<'lri> Block<'lri> {
-	/// Get a slice to the entire body of this block
-	pub fn body(&self) -> &[u8] {
-		&self.data[32..]
+	/// Whether the file ended before this block did
+	pub fn is_truncated(&self) -> bool {
+		self.data.len() < self.header.block_length
+	}
+
+	/// Get `length` bytes from `offset` into the block, making sure they're
+	/// actually there. `field` is what we tell the user was out of bounds.
+	pub fn slice(
+		&self,
+		field: &'static str,
+		offset: usize,
+		length: usize,
+	) -> Result<&'lri [u8], LriError> {
+		offset
+			.checked_add(length)
+			.and_then(|end| self.data.get(offset..end))
+			.ok_or(LriError::OutOfBounds {
+				block: self.index,
+				offset: self.offset.saturating_add(offset),
+				field,
+				length,
+			})
 	}
 
 	/// Get a slice to this block's messge data
-	pub fn message_data(&self) -> &[u8] {
-		let end = self.header.message_offset + self.header.message_length;
-		&self.data[self.header.message_offset..end]
+	pub fn message_data(&self) -> Result<&[u8], LriError> {
+		self.slice(
+			"message",
+			self.header.message_offset,
+			self.header.message_length,
+		)
 	}
 
 	/// Parse the message
-	pub fn message(&self) -> Message {
+	pub fn message(&self) -> Result<Message, LriError> {
+		let data = self.message_data()?;
+		let err = |message: &'static str, e: lri_proto::Error| LriError::Message {
+			block: self.index,
+			offset: self.offset + self.header.message_offset,
+			message,
+			reason: e.to_string(),
+		};
+
 		match self.header.kind {
-			BlockType::LightHeader => {
-				Message::LightHeader(LightHeader::parse_from_bytes(self.message_data()).unwrap())
-			}
-			BlockType::ViewPreferences => Message::ViewPreferences(
-				ViewPreferences::parse_from_bytes(self.message_data()).unwrap(),
-			),
-			BlockType::GPSData => {
-				Message::Gps(GPSData::parse_from_bytes(self.message_data()).unwrap())
-			}
+			BlockType::LightHeader => LightHeader::parse_from_bytes(data)
+				.map(Message::LightHeader)
+				.map_err(|e| err("LightHeader", e)),
+			BlockType::ViewPreferences => ViewPreferences::parse_from_bytes(data)
+				.map(Message::ViewPreferences)
+				.map_err(|e| err("ViewPreferences", e)),
+			BlockType::GPSData => GPSData::parse_from_bytes(data)
+				.map(Message::Gps)
+				.map_err(|e| err("GPSData", e)),
+		}
+	}
+
+	/// An error for a field in this block's message
+	fn missing(&self, field: &'static str) -> LriError {
+		LriError::MissingField {
+			block: self.index,
+			offset: self.offset + self.header.message_offset,
+			field,
+		}
+	}
+
+	/// An error for a value we don't understand in this block's message
+	fn unsupported(&self, field: &'static str, value: i64) -> LriError {
+		LriError::UnsupportedValue {
+			block: self.index,
+			offset: self.offset + self.header.message_offset,
+			field,
+			value,
 		}
 	}
 
@@ -49,24 +104,43 @@ impl<'lri> Block<'lri> {
 		images: &mut Vec<RawImage<'lri>>,
 		colors: &mut Vec<ColorInfo>,
 		infos: &mut Vec<CameraInfo>,
-	) {
+	) -> Result<(), LriError> {
 		let LightHeader {
+			image_unique_id_low,
+			image_unique_id_high,
+			image_time_stamp,
 			mut hw_info,
 			module_calibration,
+			device_calibration,
 			modules,
 			image_reference_camera,
+			device_unique_id_low,
+			device_unique_id_high,
+			device_model_name,
 			device_fw_version,
+			device_asic_fw_version,
 			image_focal_length,
 			af_info,
 			mut view_preferences,
+			gps_data,
+			sensor_data,
 			..
-		} = if let Message::LightHeader(lh) = self.message() {
-			lh
-		} else if let Message::ViewPreferences(vp) = self.message() {
-			self.extract_view(vp, ext);
-			return;
-		} else {
-			return;
+		} = match self.message() {
+			// The file ended before the message did. Whatever was in it is gone
+			Err(LriError::OutOfBounds { .. }) if self.is_truncated() => {
+				ext.lost_message = true;
+				return Ok(());
+			}
+			Err(e) => return Err(e),
+			Ok(Message::LightHeader(lh)) => lh,
+			Ok(Message::ViewPreferences(vp)) => {
+				self.extract_view(vp, ext);
+				return Ok(());
+			}
+			Ok(Message::Gps(gps)) => {
+				ext.gps.get_or_insert(gps.into());
+				return Ok(());
+			}
 		};
 
 		// Form the CameraInfo struct for mapping CameraId to SensorType
@@ -85,12 +159,127 @@ impl<'lri> Block<'lri> {
 			self.extract_view(vp, ext);
 		}
 
+		// Black and white levels and noise for each kind of sensor
+		for sd in sensor_data {
+			let sensor = sd.type_().into();
+			let Some(data) = sd.data.into_option() else {
+				continue;
+			};
+
+			// Every LightHeader seems to carry them, only keep the first
+			if ext.sensor_profiles.iter().any(|sp| sp.sensor == sensor) {
+				continue;
+			}
+
+			ext.sensor_profiles.push(SensorProfile {
+				sensor,
+				black_level: data.black_level(),
+				white_level: data.white_level(),
+				cliff_slope: data.cliff_slope,
+				noise_models: data.vst_model.iter().map(<_>::into).collect(),
+				time: sd.time_stamp.into_option().map(<_>::into),
+			});
+		}
+
 		// Color information for the Camera moduels.
-		for mcal in module_calibration {
+		for mut mcal in module_calibration {
 			let camera = mcal.camera_id().into();
 
+			let calibration = ModuleCalibration::find_or_insert(&mut ext.calibrations, camera);
+			if let Some(ts) = mcal.time_stamp.take() {
+				calibration.time.get_or_insert(ts.into());
+			}
+
+			if let Some(mut geometry) = mcal.geometry.take() {
+				if let Some(distortion) = geometry.distortion.take() {
+					calibration.distortion.get_or_insert(distortion.into());
+				}
+
+				calibration.geometry.get_or_insert((&geometry).into());
+			}
+
+			if let Some(hpm) = mcal.hot_pixel_map.take() {
+				for hot in hpm.data {
+					let Some(pixels) =
+						self.pixel_list("hot_pixel_map", hot.data_offset(), hot.data_size())
+					else {
+						continue;
+					};
+
+					calibration.hot_pixels.push(HotPixelMeasurement {
+						exposure: Duration::from_nanos(hot.data_exposure() as u64),
+						temperature: hot.sensor_temparature(),
+						gain: hot.sensor_gain(),
+						pixel_variance: hot.pixel_variance,
+						threshold: hot.threshold,
+						pixels,
+					});
+				}
+			}
+
+			if let Some(dpm) = mcal.dead_pixel_map.take() {
+				if let Some(pixels) =
+					self.pixel_list("dead_pixel_map", dpm.data_offset(), dpm.data_size())
+				{
+					calibration.dead_pixels.get_or_insert(DeadPixelMap {
+						black_level_threshold: dpm.black_level_threshold,
+						pixels,
+					});
+				}
+			}
+
+			if let Some(mut vc) = mcal.vignetting.take() {
+				if let Some(ct) = vc.crosstalk.take() {
+					let (width, height) = (ct.width() as usize, ct.height() as usize);
+
+					// It's either in the matrices or packed, never seen both. A
+					// grid that doesn't add up isn't worth failing the file over.
+					let grid = if ct.data_packed.is_empty() {
+						let data = ct
+							.data
+							.into_iter()
+							.map(Self::deconstruct_matrix4x4)
+							.collect();
+						CrosstalkGrid::new(width, height, data)
+					} else {
+						CrosstalkGrid::from_packed(width, height, &ct.data_packed)
+					};
+
+					if let Some(grid) = grid {
+						calibration.crosstalk.get_or_insert(grid);
+					}
+				}
+
+				let mut models = vec![];
+				for mut mirror in vc.vignetting {
+					let Some(model) = mirror.vignetting.take() else {
+						continue;
+					};
+
+					// A grid that doesn't add up isn't worth failing the file over
+					let (width, height) = (model.width() as usize, model.height() as usize);
+					let Some(grid) = VignettingGrid::new(width, height, model.data) else {
+						continue;
+					};
+
+					models.push(MirrorVignetting {
+						mirror_position: mirror.hall_code(),
+						grid,
+					});
+				}
+
+				calibration.vignetting.get_or_insert(Vignetting {
+					models,
+					relative_brightness: vc.relative_brightness,
+					lens_position: vc.lens_hall_code,
+				});
+			}
+
 			for mut color in mcal.color {
-				let whitepoint = color.type_().into();
+				let whitepoint = color
+					.type_()
+					.try_into()
+					.map_err(|it| self.unsupported("color.type", it as i64))?;
 				let forward_matrix = match color.forward_matrix.take() {
 					Some(fw) => Self::deconstruct_matrix3x3(fw),
 					// The forward matrix is like, what we want! If we don't get it, don't bother
@@ -125,61 +314,73 @@ impl<'lri> Block<'lri> {
 				None => continue,
 			};
 
-			let size = surface.size.take().unwrap();
-			let width = size.x() as usize;
-			let height = size.y() as usize;
+			let size = surface
+				.size
+				.take()
+				.ok_or_else(|| self.missing("sensor_data_surface.size"))?;
+			let width = usize::try_from(size.x())
+				.map_err(|_| self.unsupported("sensor_data_surface.size.x", size.x() as i64))?;
+			let height = usize::try_from(size.y())
+				.map_err(|_| self.unsupported("sensor_data_surface.size.y", size.y() as i64))?;
+
+			// A corrupt size can be big enough that working out how much data
+			// the image takes overflows
+			if width.checked_mul(height).is_none() || width.checked_mul(16).is_none() {
+				return Err(self.unsupported("sensor_data_surface.size.x", width as i64));
+			}
 
 			let offset = surface.data_offset() as usize;
-			let data_length = surface.row_stride() as usize * height;
-
-			let format = surface.format().into();
+			let row_stride = surface.row_stride() as usize;
+			let data_length = row_stride.saturating_mul(height);
+
+			let format: DataFormat = surface
+				.format()
+				.try_into()
+				.map_err(|ft| self.unsupported("sensor_data_surface.format", ft as i64))?;
+
+			// Make sure a row of pixels actually fits in a row of data so we
+			// can unpack it later
+			if let Some(bits) = format.bits_per_pixel() {
+				if unpack::row_length(bits, width) > row_stride {
+					return Err(
+						self.unsupported("sensor_data_surface.row_stride", row_stride as i64)
+					);
+				}
+			}
 			let image_data = match format {
-				DataFormat::BayerJpeg => {
-					let bjpg_header_len = 1576;
-					let mut wrk = &self.data[offset..];
-
-					let format = u32::from_le_bytes(wrk[4..8].try_into().unwrap());
-
-					let jpeg0_len = u32::from_le_bytes(wrk[8..12].try_into().unwrap()) as usize;
-					let jpeg1_len = u32::from_le_bytes(wrk[12..16].try_into().unwrap()) as usize;
-					let jpeg2_len = u32::from_le_bytes(wrk[16..20].try_into().unwrap()) as usize;
-					let jpeg3_len = u32::from_le_bytes(wrk[20..24].try_into().unwrap()) as usize;
-
-					let mut get = |len: usize| -> &[u8] {
-						let data = &wrk[..len];
-						wrk = &wrk[len..];
-						data
-					};
+				DataFormat::BayerJpeg => self.bayer_jpeg(camera, offset),
+				DataFormat::Packed10bpp => self
+					.slice("sensor_data_surface", offset, data_length)
+					.map(|data| RawData::Packed10bpp { data }),
+				DataFormat::Packed12bpp => self
+					.slice("sensor_data_surface", offset, data_length)
+					.map(|data| RawData::Packed12bpp { data }),
+				DataFormat::Packed14bpp => self
+					.slice("sensor_data_surface", offset, data_length)
+					.map(|data| RawData::Packed14bpp { data }),
+			};
 
-					let header = get(bjpg_header_len);
-					let jpeg0 = get(jpeg0_len);
-
-					match format {
-						1 => RawData::BayerJpeg {
-							header,
-							format,
-							jpeg0,
-							jpeg1: &wrk[0..0],
-							jpeg2: &wrk[0..0],
-							jpeg3: &wrk[0..0],
-						},
-						0 => RawData::BayerJpeg {
-							header,
-							format,
-							jpeg0,
-							jpeg1: get(jpeg1_len),
-							jpeg2: get(jpeg2_len),
-							jpeg3: get(jpeg3_len),
-						},
-						_ => unreachable!(),
-					}
+			let image_data = match image_data {
+				Ok(data) => data,
+				// Part of the image was past where the file was cut off
+				Err(LriError::OutOfBounds { .. } | LriError::TruncatedBayerJpeg { .. })
+					if self.is_truncated() =>
+				{
+					ext.lost_modules.push(camera);
+					continue;
 				}
-				DataFormat::Packed10bpp => RawData::Packed10bpp {
-					data: &self.data[offset..offset + data_length],
-				},
+				Err(e) => return Err(e),
 			};
 
-			let sbro = module.sensor_bayer_red_override.clone().unwrap();
+			let start = surface
+				.start
+				.take()
+				.ok_or_else(|| self.missing("sensor_data_surface.start"))?;
+
+			let sbro = module
+				.sensor_bayer_red_override
+				.take()
+				.ok_or_else(|| self.missing("sensor_bayer_red_override"))?;
 
 			images.push(RawImage {
 				camera,
@@ -187,11 +388,18 @@ impl<'lri> Block<'lri> {
 				sensor: SensorModel::Unknown,
 				width,
 				height,
+				row_stride,
 				format,
 				data: image_data,
+				capture: ModuleCapture::from(&module),
 				sbro: (sbro.x(), sbro.y()),
+				start: (start.x(), start.y()),
+				horizontal_flip: module.sensor_is_horizontal_flip(),
+				vertical_flip: module.sensor_is_vertical_flip(),
 				// Populated after all the blocks are processed
 				color: vec![],
+				// Populated after all the blocks are processed
+				sensor_profile: None,
 			});
 		}
 
@@ -203,13 +411,126 @@ impl<'lri> Block<'lri> {
 			ext.af_achieved.get_or_insert(afd.focus_achieved());
 		}
 
+		if let Some(id) = ImageId::from_halves(image_unique_id_low, image_unique_id_high) {
+			ext.image_id.get_or_insert(id);
+		}
+
+		if let Some(id) = DeviceId::from_halves(device_unique_id_low, device_unique_id_high) {
+			ext.device_id.get_or_insert(id);
+		}
+
+		if let Some(name) = device_model_name {
+			ext.model_name.get_or_insert(name);
+		}
+
 		if let Some(fwv) = device_fw_version {
 			ext.fw_version.get_or_insert(fwv);
 		}
 
+		if let Some(fwv) = device_asic_fw_version {
+			ext.asic_fw_version.get_or_insert(fwv);
+		}
+
 		if let Some(x) = image_focal_length {
 			ext.focal_length.get_or_insert(x);
 		}
+
+		if let Some(ts) = image_time_stamp.into_option() {
+			ext.capture_time.get_or_insert(ts.into());
+		}
+
+		if let Some(ts) = device_calibration
+			.into_option()
+			.and_then(|dc| dc.time_stamp.into_option())
+		{
+			ext.device_calibration_time.get_or_insert(ts.into());
+		}
+
+		if let Some(gps) = gps_data.into_option() {
+			ext.gps.get_or_insert(gps.into());
+		}
+
+		Ok(())
+	}
+
+	/// Split the BayerJPEG at `offset` into its header and the JPEGs themselves
+	fn bayer_jpeg(&self, camera: CameraId, offset: usize) -> Result<RawData<'lri>, LriError> {
+		let truncated = || LriError::TruncatedBayerJpeg {
+			block: self.index,
+			offset: self.offset.saturating_add(offset),
+			camera,
+		};
+
+		let mut wrk = self.data.get(offset..).ok_or_else(truncated)?;
+		if wrk.len() < BayerJpegHeader::LENGTH {
+			return Err(truncated());
+		}
+
+		let header = BayerJpegHeader::parse(wrk).ok_or_else(|| LriError::BadBayerJpegMagic {
+			block: self.index,
+			offset: self.offset + offset,
+			camera,
+			found: wrk[0..4].try_into().unwrap(),
+		})?;
+		wrk = &wrk[BayerJpegHeader::LENGTH..];
+
+		let mut get = |len: u32| -> Result<&'lri [u8], LriError> {
+			let len = len as usize;
+			if wrk.len() < len {
+				return Err(truncated());
+			}
+
+			let (data, rest) = wrk.split_at(len);
+			wrk = rest;
+			Ok(data)
+		};
+
+		let [jpeg0_len, jpeg1_len, jpeg2_len, jpeg3_len] = header.jpeg_lengths;
+		let jpeg0 = get(jpeg0_len)?;
+
+		match header.format {
+			1 => Ok(RawData::BayerJpeg {
+				header,
+				jpeg0,
+				jpeg1: &[],
+				jpeg2: &[],
+				jpeg3: &[],
+			}),
+			0 => Ok(RawData::BayerJpeg {
+				header,
+				jpeg0,
+				jpeg1: get(jpeg1_len)?,
+				jpeg2: get(jpeg2_len)?,
+				jpeg3: get(jpeg3_len)?,
+			}),
+			format => Err(LriError::UnsupportedValue {
+				block: self.index,
+				offset: self.offset + offset + 4,
+				field: "BayerJPEG format",
+				value: format as i64,
+			}),
+		}
+	}
+
+	/// Read a list of pixel coordinates, like a hot pixel map, from `offset`
+	/// into the block. Returns `None` if the list isn't all inside the block.
+	///
+	/// The layout is a guess, see [HotPixelMeasurement], so a list we can't
+	/// find isn't worth failing the file over.
+	fn pixel_list(&self, field: &'static str, offset: u64, length: u32) -> Option<Vec<(u16, u16)>> {
+		let data = self.slice(field, offset as usize, length as usize).ok()?;
+
+		let pixels = data
+			.chunks_exact(4)
+			.map(|xy| {
+				(
+					u16::from_le_bytes([xy[0], xy[1]]),
+					u16::from_le_bytes([xy[2], xy[3]]),
+				)
+			})
+			.collect();
+
+		Some(pixels)
 	}
 
 	// It kept making my neat little array very, very tall
@@ -222,8 +543,19 @@ impl<'lri> Block<'lri> {
 		]
 	}
 
+	#[rustfmt::skip]
+	fn deconstruct_matrix4x4(mat: Matrix4x4F) -> [f32; 16] {
+		[
+			mat.x00(), mat.x01(), mat.x02(), mat.x03(),
+			mat.x10(), mat.x11(), mat.x12(), mat.x13(),
+			mat.x20(), mat.x21(), mat.x22(), mat.x23(),
+			mat.x30(), mat.x31(), mat.x32(), mat.x33(),
+		]
+	}
+
 	fn extract_view(&self, vp: ViewPreferences, ext: &mut ExtractedData) {
 		let ViewPreferences {
+			f_number,
 			image_integration_time_ns,
 			image_gain,
 			hdr_mode,
@@ -234,6 +566,10 @@ impl<'lri> Block<'lri> {
 			..
 		} = vp;
 
+		if let Some(f) = f_number {
+			ext.f_number = Some(f);
+		}
+
 		if let Some(ns) = image_integration_time_ns {
 			ext.image_integration_time = Some(Duration::from_nanos(ns));
 		}
@@ -267,11 +603,16 @@ impl<'lri> Block<'lri> {
 #[derive(Debug, Default)]
 pub(crate) struct ExtractedData {
 	pub reference_camera: Option<CameraId>,
+	pub image_id: Option<ImageId>,
+	pub device_id: Option<DeviceId>,
+	pub model_name: Option<String>,
 	pub fw_version: Option<String>,
+	pub asic_fw_version: Option<String>,
 	pub focal_length: Option<i32>,
 
 	pub image_gain: Option<f32>,
 	pub image_integration_time: Option<Duration>,
+	pub f_number: Option<f32>,
 	pub af_achieved: Option<bool>,
 	pub hdr: Option<HdrMode>,
 	pub scene: Option<SceneMode>,
@@ -279,8 +620,21 @@ pub(crate) struct ExtractedData {
 
 	pub awb: Option<AwbMode>,
 	pub awb_gain: Option<AwbGain>,
+
+	pub gps: Option<GpsInfo>,
+
+	pub capture_time: Option<DateTime>,
+	pub device_calibration_time: Option<DateTime>,
+	pub calibrations: Vec<ModuleCalibration>,
+	pub sensor_profiles: Vec<SensorProfile>,
+
+	/// Modules in a truncated block whose images were cut off
+	pub lost_modules: Vec<CameraId>,
+	/// Whether a truncated block's message was cut off
+	pub lost_message: bool,
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum Message {
 	LightHeader(LightHeader),
 	ViewPreferences(ViewPreferences),
@@ -299,11 +653,24 @@ pub struct Header {
 }
 
 impl Header {
-	pub fn ingest(data: &[u8]) -> Self {
+	/// Length of the header in bytes
+	pub const LENGTH: usize = 32;
+
+	/// Read the header at the start of `data`. `block` and `offset` are the
+	/// block's index and position in the file and only used for errors.
+	pub fn ingest(data: &[u8], block: usize, offset: usize) -> Result<Self, LriError> {
 		let magic = b"LELR";
 
+		if data.len() < Self::LENGTH {
+			return Err(LriError::TruncatedHeader { block, offset });
+		}
+
 		if &data[0..4] != magic {
-			panic!("Magic nubmer is wrong");
+			return Err(LriError::BadMagic {
+				block,
+				offset,
+				found: data[0..4].try_into().unwrap(),
+			});
 		}
 
 		let combined_length = u64::from_le_bytes(data[4..12].try_into().unwrap()) as usize;
@@ -314,15 +681,21 @@ impl Header {
 			0 => BlockType::LightHeader,
 			1 => BlockType::ViewPreferences,
 			2 => BlockType::GPSData,
-			t => panic!("block type {t} is unknown"),
+			kind => {
+				return Err(LriError::UnknownBlockType {
+					block,
+					offset,
+					kind,
+				})
+			}
 		};
 
-		Header {
+		Ok(Header {
 			block_length: combined_length,
 			message_offset,
 			message_length,
 			kind,
-		}
+		})
 	}
 }
 
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use crate::{CameraId, CrosstalkGrid, DateTime, Distortion, Geometry, Vignetting};
+
+/// Factory calibration for a single camera module
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleCalibration {
+	pub camera: CameraId,
+	/// When the module was calibrated
+	pub time: Option<DateTime>,
+	/// Hot pixels, measured under a few different conditions
+	pub hot_pixels: Vec<HotPixelMeasurement>,
+	pub dead_pixels: Option<DeadPixelMap>,
+	pub vignetting: Option<Vignetting>,
+	/// Stored alongside the vignetting, but it doesn't change with the mirror
+	pub crosstalk: Option<CrosstalkGrid>,
+	pub distortion: Option<Distortion>,
+	/// Intrinsics and extrinsics at different focus distances
+	pub geometry: Option<Geometry>,
+}
+
+impl ModuleCalibration {
+	pub(crate) fn new(camera: CameraId) -> Self {
+		Self {
+			camera,
+			time: None,
+			hot_pixels: vec![],
+			dead_pixels: None,
+			vignetting: None,
+			crosstalk: None,
+			distortion: None,
+			geometry: None,
+		}
+	}
+
+	/// Get the calibration for `camera` out of the list, adding an empty one
+	/// if it isn't there yet. The calibration for a module can be split
+	/// across the LightHeaders in multiple blocks.
+	pub(crate) fn find_or_insert(list: &mut Vec<Self>, camera: CameraId) -> &mut Self {
+		match list.iter().position(|cal| cal.camera == camera) {
+			Some(idx) => &mut list[idx],
+			None => {
+				list.push(Self::new(camera));
+				list.last_mut().unwrap()
+			}
+		}
+	}
+}
+
+/// The hot pixels of a module at one exposure, temperature and gain.
+///
+/// The maps themselves are stored outside the protobuf message in a format
+/// nobody has documented. We read them as a list of little-endian `u16` x and
+/// y pairs in sensor coordinates, but that's a guess that hasn't been checked
+/// against a real file, so `pixels` may well be garbage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotPixelMeasurement {
+	/// Assumed to be nanoseconds, like the module's exposure time. Also
+	/// unchecked.
+	pub exposure: Duration,
+	/// Temperature of the sensor, probably in °C
+	pub temperature: i32,
+	pub gain: f32,
+	pub pixel_variance: Option<f32>,
+	pub threshold: Option<f32>,
+	pub pixels: Vec<(u16, u16)>,
+}
+
+/// Pixels that don't respond to light. We read them the same way as the
+/// [HotPixelMeasurement] pixels, which is just as much of a guess.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadPixelMap {
+	pub black_level_threshold: Option<f32>,
+	pub pixels: Vec<(u16, u16)>,
+}
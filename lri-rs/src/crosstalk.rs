@@ -0,0 +1,285 @@
+//! Colour crosstalk correction.
+//!
+//! Light that lands on one photosite can leak into its neighbours, so each
+//! sample is a bit of a mix of the colours around it. The factory measured
+//! that as a grid of 4x4 matrices over the frame. We take each to be the
+//! leakage, taking the true colours of a 2x2 cell in the order R, Gr, Gb, B
+//! to what the sensor recorded, so correcting multiplies by its inverse. Gr
+//! is the green on the same row as red, Gb the one on the same row as blue.
+//!
+//! The grid is laid out like a [VignettingGrid](crate::VignettingGrid), its
+//! corners are the corners of the sensor.
+
+use crate::{vignetting::bilinear, ModuleCalibration, RawImage};
+
+/// A `width` by `height` grid of row-major 4x4 matrices, in rows
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrosstalkGrid {
+	width: usize,
+	height: usize,
+	data: Vec<[f32; 16]>,
+}
+
+impl CrosstalkGrid {
+	/// Returns `None` if `data` isn't `width * height` long or the grid is empty
+	pub fn new(width: usize, height: usize, data: Vec<[f32; 16]>) -> Option<Self> {
+		let len = width.checked_mul(height)?;
+		(len > 0 && data.len() == len).then_some(Self {
+			width,
+			height,
+			data,
+		})
+	}
+
+	/// Build the grid out of the packed representation, sixteen floats per
+	/// matrix. Returns `None` if there aren't `width * height` matrices.
+	pub fn from_packed(width: usize, height: usize, packed: &[f32]) -> Option<Self> {
+		// The size comes from the file, so it can be big enough to overflow
+		let len = width.checked_mul(height).and_then(|n| n.checked_mul(16))?;
+		if packed.len() != len {
+			return None;
+		}
+
+		let data = packed
+			.chunks_exact(16)
+			.map(|mat| mat.try_into().unwrap())
+			.collect();
+
+		Self::new(width, height, data)
+	}
+
+	pub fn width(&self) -> usize {
+		self.width
+	}
+
+	pub fn height(&self) -> usize {
+		self.height
+	}
+
+	pub fn data(&self) -> &[[f32; 16]] {
+		&self.data
+	}
+
+	/// Bilinearly interpolate the grid. `u` and `v` go from 0.0 at the left and
+	/// top to 1.0 at the right and bottom and are clamped to that.
+	pub fn sample(&self, u: f32, v: f32) -> [f32; 16] {
+		let mut out = [0.0; 16];
+
+		for (idx, weight) in bilinear(self.width, self.height, u, v) {
+			for (o, m) in out.iter_mut().zip(self.data[idx]) {
+				*o += m * weight;
+			}
+		}
+
+		out
+	}
+}
+
+impl<'img> RawImage<'img> {
+	/// Undo the crosstalk in a linear, black level subtracted, mosaic in the
+	/// order it's stored in the file, like what [RawImage::denoise] gives you.
+	/// The mosaic is moved by [RawImage::start] the same way
+	/// [RawImage::flat_field] does it.
+	///
+	/// Returns `false`, leaving `mosaic` untouched, if `calibration` has no
+	/// crosstalk model, the image isn't colour, or a matrix can't be inverted.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn correct_crosstalk(&self, calibration: &ModuleCalibration, mosaic: &mut [f32]) -> bool {
+		let Some(grid) = calibration.crosstalk.as_ref() else {
+			return false;
+		};
+		let Some(cfa) = self.stored_cfa_string() else {
+			return false;
+		};
+
+		let (start_x, start_y) = (self.start.0.max(0) as usize, self.start.1.max(0) as usize);
+		let sensor = (start_x + self.width, start_y + self.height);
+
+		correct(
+			mosaic,
+			self.width,
+			self.height,
+			(start_x, start_y),
+			sensor,
+			cfa,
+			grid,
+		)
+	}
+}
+
+/// Undo the crosstalk in every 2x2 cell of `mosaic` with the inverse of the
+/// matrices around its center, `grid` being stretched over a sensor
+/// `sensor_size` big. `offset` is where on the sensor the top-left of the
+/// mosaic is and `cfa` is the CFA of the mosaic as it's laid out in `mosaic`.
+///
+/// Returns `false`, leaving `mosaic` untouched, if `cfa` isn't made of one red,
+/// two green, and one blue or a matrix of the grid can't be inverted.
+///
+/// # Panics
+/// If `mosaic` isn't `width * height` long
+pub fn correct(
+	mosaic: &mut [f32],
+	width: usize,
+	height: usize,
+	offset: (usize, usize),
+	sensor_size: (usize, usize),
+	cfa: &str,
+	grid: &CrosstalkGrid,
+) -> bool {
+	assert_eq!(mosaic.len(), width * height);
+
+	let Some(order) = channel_order(cfa) else {
+		return false;
+	};
+
+	// The matrices change slowly across the grid, so interpolating their
+	// inverses is as good as inverting the interpolated matrix
+	let Some(inverses) = grid.data.iter().map(invert).collect() else {
+		return false;
+	};
+	let grid = CrosstalkGrid {
+		data: inverses,
+		..*grid
+	};
+
+	// Where the center of a cell is from 0.0 to 1.0 across the sensor
+	let center =
+		|n: usize, start: usize, size: usize| ((n + start) as f32 + 0.5) / (size.max(2) - 1) as f32;
+	for y in (0..height - height % 2).step_by(2) {
+		for x in (0..width - width % 2).step_by(2) {
+			let cell = order.map(|pos| (y + pos / 2) * width + x + pos % 2);
+			let samples = cell.map(|idx| mosaic[idx]);
+			let mat = grid.sample(
+				center(x, offset.0, sensor_size.0),
+				center(y, offset.1, sensor_size.1),
+			);
+
+			for (row, idx) in cell.into_iter().enumerate() {
+				mosaic[idx] = (0..4).map(|col| mat[row * 4 + col] * samples[col]).sum();
+			}
+		}
+	}
+
+	true
+}
+
+/// Where R, Gr, Gb, and B are in a 2x2 cell, numbered left to right and top
+/// to bottom.
+fn channel_order(cfa: &str) -> Option<[usize; 4]> {
+	let cfa = cfa.as_bytes();
+	if cfa.len() != 4 {
+		return None;
+	}
+
+	let find = |color: u8| cfa.iter().position(|c| *c == color);
+	let red = find(b'R')?;
+	let blue = find(b'B')?;
+
+	// The green that shares a row with red
+	let green_red = red ^ 1;
+	let green_blue = blue ^ 1;
+
+	(cfa[green_red] == b'G' && cfa[green_blue] == b'G' && green_red != green_blue)
+		.then_some([red, green_red, green_blue, blue])
+}
+
+/// Invert a row-major 4x4 matrix by Gauss-Jordan elimination. `None` if it's
+/// singular.
+fn invert(mat: &[f32; 16]) -> Option<[f32; 16]> {
+	let mut a = *mat;
+	let mut inv = [0.0; 16];
+	for i in 0..4 {
+		inv[i * 4 + i] = 1.0;
+	}
+
+	for col in 0..4 {
+		// Swap the row with the biggest value in this column up, so we don't
+		// divide by anything tiny
+		let pivot = (col..4).max_by(|a_row, b_row| {
+			a[a_row * 4 + col]
+				.abs()
+				.total_cmp(&a[b_row * 4 + col].abs())
+		})?;
+		if a[pivot * 4 + col].abs() < f32::EPSILON {
+			return None;
+		}
+
+		for k in 0..4 {
+			a.swap(col * 4 + k, pivot * 4 + k);
+			inv.swap(col * 4 + k, pivot * 4 + k);
+		}
+
+		let divisor = a[col * 4 + col];
+		for k in 0..4 {
+			a[col * 4 + k] /= divisor;
+			inv[col * 4 + k] /= divisor;
+		}
+
+		for row in (0..4).filter(|row| *row != col) {
+			let factor = a[row * 4 + col];
+			for k in 0..4 {
+				a[row * 4 + k] -= factor * a[col * 4 + k];
+				inv[row * 4 + k] -= factor * inv[col * 4 + k];
+			}
+		}
+	}
+
+	Some(inv)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[rustfmt::skip]
+	const LEAKAGE: [f32; 16] = [
+		0.90, 0.05, 0.03, 0.02,
+		0.04, 0.92, 0.01, 0.03,
+		0.03, 0.01, 0.93, 0.03,
+		0.02, 0.04, 0.06, 0.88,
+	];
+
+	#[test]
+	fn grid_sizes() {
+		assert!(CrosstalkGrid::from_packed(1, 2, &[0.0; 32]).is_some());
+		assert!(CrosstalkGrid::from_packed(2, 2, &[0.0; 32]).is_none());
+		assert!(CrosstalkGrid::from_packed(0, 0, &[]).is_none());
+		assert!(CrosstalkGrid::from_packed(usize::MAX, 2, &[]).is_none());
+		assert!(CrosstalkGrid::from_packed(1 << 31, 1 << 31, &[]).is_none());
+		assert!(CrosstalkGrid::new(usize::MAX, usize::MAX, vec![]).is_none());
+	}
+
+	#[test]
+	fn invert_leakage() {
+		let inv = invert(&LEAKAGE).unwrap();
+		for row in 0..4 {
+			for col in 0..4 {
+				let product: f32 = (0..4)
+					.map(|k| LEAKAGE[row * 4 + k] * inv[k * 4 + col])
+					.sum();
+				let identity = if row == col { 1.0 } else { 0.0 };
+				assert!((product - identity).abs() < 1e-5);
+			}
+		}
+
+		assert_eq!(invert(&[0.0; 16]), None);
+	}
+
+	#[test]
+	fn correct_undoes_leakage() {
+		// An RGGB cell, so R, Gr, Gb, B are in the order they're stored
+		let truth = [0.6, 0.3, 0.25, 0.1];
+		let mut mosaic: Vec<f32> = (0..4)
+			.map(|row| (0..4).map(|col| LEAKAGE[row * 4 + col] * truth[col]).sum())
+			.collect();
+
+		let grid = CrosstalkGrid::new(2, 2, vec![LEAKAGE; 4]).unwrap();
+		assert!(correct(&mut mosaic, 2, 2, (0, 0), (2, 2), "RGGB", &grid));
+
+		for (corrected, truth) in mosaic.iter().zip(truth) {
+			assert!((corrected - truth).abs() < 1e-5);
+		}
+	}
+}
@@ -0,0 +1,113 @@
+//! Correcting hot and dead pixels with the factory maps.
+//!
+//! A defective pixel is replaced with the median of the closest pixels of the
+//! same CFA colour, which are two away in every direction.
+//!
+//! How the maps are stored is a guess, see [HotPixelMeasurement], so
+//! [RawImage::linear_mosaic] only does this if
+//! [MosaicOptions::defects](crate::MosaicOptions::defects) asks it to.
+
+use crate::{HotPixelMeasurement, ModuleCalibration, ModuleCapture, RawImage};
+
+impl ModuleCalibration {
+	/// The hot pixel measurement taken closest to how `capture` was taken.
+	///
+	/// Exposure and gain are compared by their ratio, so twice as long counts
+	/// the same as half as long, and each 10°C of temperature difference counts
+	/// the same as a doubling. The temperature is ignored if the module didn't
+	/// record one.
+	pub fn hot_pixels_for(&self, capture: &ModuleCapture) -> Option<&HotPixelMeasurement> {
+		let distance = |hot: &HotPixelMeasurement| {
+			let ratio = |a: f32, b: f32| (a.max(f32::EPSILON) / b.max(f32::EPSILON)).log2();
+
+			let exposure = ratio(hot.exposure.as_secs_f32(), capture.exposure.as_secs_f32());
+			let gain = ratio(hot.gain, capture.total_gain());
+			let temperature = capture
+				.temperature
+				.map(|t| (hot.temperature - t) as f32 / 10.0)
+				.unwrap_or(0.0);
+
+			exposure * exposure + gain * gain + temperature * temperature
+		};
+
+		self.hot_pixels
+			.iter()
+			.min_by(|a, b| distance(a).total_cmp(&distance(b)))
+	}
+
+	/// Every dead pixel and the hot pixels from [ModuleCalibration::hot_pixels_for]
+	/// in sensor coordinates.
+	pub fn defects(&self, capture: &ModuleCapture) -> Vec<(u16, u16)> {
+		let dead = self.dead_pixels.iter().flat_map(|dpm| dpm.pixels.iter());
+		let hot = self
+			.hot_pixels_for(capture)
+			.into_iter()
+			.flat_map(|hot| hot.pixels.iter());
+
+		dead.chain(hot).copied().collect()
+	}
+}
+
+impl<'img> RawImage<'img> {
+	/// Correct the defective pixels of an unpacked image, in the order it's
+	/// stored in the file. `calibration` should be the one for this image's
+	/// camera.
+	///
+	/// The maps are in sensor coordinates, so they're moved by the
+	/// [RawImage::start] of the image and pixels outside it are skipped.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn correct_defects(&self, calibration: &ModuleCalibration, mosaic: &mut [u16]) {
+		let (start_x, start_y) = self.start;
+
+		let defects: Vec<(usize, usize)> = calibration
+			.defects(&self.capture)
+			.into_iter()
+			.filter_map(|(x, y)| {
+				let x = usize::try_from(x as i32 - start_x).ok()?;
+				let y = usize::try_from(y as i32 - start_y).ok()?;
+				(x < self.width && y < self.height).then_some((x, y))
+			})
+			.collect();
+
+		correct(mosaic, self.width, self.height, &defects);
+	}
+}
+
+/// Replace every pixel in `defects` with the median of its same-colour
+/// neighbours that aren't defective themselves. Pixels with no good
+/// neighbours are left alone.
+///
+/// # Panics
+/// If `mosaic` isn't `width * height` long or a defect is outside of it
+pub fn correct(mosaic: &mut [u16], width: usize, height: usize, defects: &[(usize, usize)]) {
+	assert_eq!(mosaic.len(), width * height);
+
+	let mut bad = vec![false; mosaic.len()];
+	for (x, y) in defects {
+		bad[y * width + x] = true;
+	}
+
+	for (x, y) in defects {
+		let mut neighbours: Vec<u16> = [-2, 0, 2]
+			.into_iter()
+			.flat_map(|dy| [-2, 0, 2].into_iter().map(move |dx| (dx, dy)))
+			.filter(|&(dx, dy)| dx != 0 || dy != 0)
+			.filter_map(|(dx, dy)| {
+				let nx = x.checked_add_signed(dx).filter(|nx| *nx < width)?;
+				let ny = y.checked_add_signed(dy).filter(|ny| *ny < height)?;
+				let idx = ny * width + nx;
+
+				(!bad[idx]).then_some(mosaic[idx])
+			})
+			.collect();
+
+		if neighbours.is_empty() {
+			continue;
+		}
+
+		neighbours.sort_unstable();
+		mosaic[y * width + x] = neighbours[neighbours.len() / 2];
+	}
+}
@@ -0,0 +1,207 @@
+//! Denoising mosaics with the noise models from the sensor characterization.
+//!
+//! Each channel's noise is modeled as having a variance of `a * x + b` for a
+//! sample `x`. The generalized Anscombe transform turns that into noise with
+//! a variance of about one everywhere, which is a lot easier to filter. We
+//! filter each CFA channel on its own with a local Wiener filter and then
+//! transform back.
+//!
+//! The noise models also have a `threshold` and `scale` and we don't know what
+//! either of them are for, so they aren't used. Which model goes with a module
+//! is a guess too, see [SensorProfile::noise_model](crate::SensorProfile::noise_model).
+
+use crate::{image::box_mean, NoiseModel, RawImage, VstModel};
+
+/// Radius of the window used for the local statistics, in samples of one channel
+const RADIUS: usize = 2;
+
+impl VstModel {
+	/// The generalized Anscombe transform of `x`
+	pub fn forward(&self, x: f32) -> f32 {
+		if self.a > 0.0 {
+			2.0 / self.a
+				* (self.a * x + 0.375 * self.a * self.a + self.b)
+					.max(0.0)
+					.sqrt()
+		} else {
+			// No signal dependent noise, so it's just Gaussian
+			x / self.b.max(f32::EPSILON).sqrt()
+		}
+	}
+
+	/// The algebraic inverse of [VstModel::forward]. It's slightly biased at
+	/// very low signal, but that's well below anything we'd see.
+	pub fn inverse(&self, y: f32) -> f32 {
+		if self.a > 0.0 {
+			let half = self.a * y / 2.0;
+			(half * half - 0.375 * self.a * self.a - self.b) / self.a
+		} else {
+			y * self.b.max(f32::EPSILON).sqrt()
+		}
+	}
+}
+
+impl NoiseModel {
+	/// The model for a colour in a CFA string. Anything we don't know falls
+	/// back to panchromatic if there is one, or green if there isn't.
+	pub fn channel(&self, color: char) -> &VstModel {
+		match color {
+			'R' => &self.red,
+			'G' => &self.green,
+			'B' => &self.blue,
+			_ => self.panchromatic.as_ref().unwrap_or(&self.green),
+		}
+	}
+}
+
+impl<'img> RawImage<'img> {
+	/// Unpack, black level subtract and denoise the image. The result is
+	/// scaled so the white level is 1.0.
+	///
+	/// Uses the noise model measured nearest this module's analog gain.
+	/// `strength` scales how much noise we try to remove, 1.0 is what the
+	/// noise model says is there.
+	///
+	/// Returns `None` if the image isn't packed or we don't have a noise model
+	/// for the sensor.
+	pub fn denoise(&self, strength: f32) -> Option<Vec<f32>> {
+		self.denoise_unpacked(&self.unpack()?, strength)
+	}
+
+	/// Like [RawImage::denoise] but on an image you've already unpacked, for
+	/// if you want to do something to it first, like
+	/// [RawImage::correct_defects].
+	///
+	/// # Panics
+	/// If `unpacked` isn't `width * height` long
+	pub fn denoise_unpacked(&self, unpacked: &[u16], strength: f32) -> Option<Vec<f32>> {
+		let profile = self.sensor_profile.as_ref()?;
+		let model = profile.noise_model(self.capture.analog_gain)?;
+		let (black, white) = (self.black_level()?, self.white_level()?);
+
+		// The noise model is in units of the sensor's bit depth, which might
+		// not be the same as the data.
+		let scale = profile.white_level / white;
+		let mut mosaic: Vec<f32> = unpacked
+			.iter()
+			.map(|p| (*p as f32 - black) * scale)
+			.collect();
+
+		denoise(
+			&mut mosaic,
+			self.width,
+			self.height,
+			self.stored_cfa_string(),
+			model,
+			strength,
+		);
+
+		let range = profile.white_level - profile.black_level;
+		mosaic.iter_mut().for_each(|p| *p /= range);
+		Some(mosaic)
+	}
+}
+
+/// Denoise a black level subtracted mosaic in place. `cfa` is the CFA of the
+/// mosaic as it's laid out in `mosaic`, `None` for monochrome sensors.
+///
+/// # Panics
+/// If `mosaic` isn't `width * height` long
+pub fn denoise(
+	mosaic: &mut [f32],
+	width: usize,
+	height: usize,
+	cfa: Option<&str>,
+	model: &NoiseModel,
+	strength: f32,
+) {
+	assert_eq!(mosaic.len(), width * height);
+	let noise = strength * strength;
+
+	let Some(cfa) = cfa else {
+		let vst = model.channel('P');
+		mosaic.iter_mut().for_each(|p| *p = vst.forward(*p));
+		wiener(mosaic, width, height, noise);
+		mosaic.iter_mut().for_each(|p| *p = vst.inverse(*p));
+		return;
+	};
+
+	for (idx, color) in cfa.chars().enumerate() {
+		let (off_x, off_y) = (idx % 2, idx / 2);
+		let plane_width = (width + 1 - off_x) / 2;
+		let plane_height = (height + 1 - off_y) / 2;
+		let vst = model.channel(color);
+
+		let at = |x: usize, y: usize| (y * 2 + off_y) * width + x * 2 + off_x;
+
+		let mut plane = Vec::with_capacity(plane_width * plane_height);
+		for y in 0..plane_height {
+			for x in 0..plane_width {
+				plane.push(vst.forward(mosaic[at(x, y)]));
+			}
+		}
+
+		wiener(&mut plane, plane_width, plane_height, noise);
+
+		for y in 0..plane_height {
+			for x in 0..plane_width {
+				mosaic[at(x, y)] = vst.inverse(plane[y * plane_width + x]);
+			}
+		}
+	}
+}
+
+/// Local Wiener filter, also called a Lee filter. Pulls every sample toward
+/// the mean of its neighbourhood by how much of the neighbourhood's variance
+/// is noise.
+fn wiener(data: &mut [f32], width: usize, height: usize, noise: f32) {
+	if data.is_empty() {
+		return;
+	}
+
+	let mean = box_mean(data, width, height, RADIUS);
+	let squares: Vec<f32> = data.iter().map(|p| p * p).collect();
+	let mean_squares = box_mean(&squares, width, height, RADIUS);
+
+	for (idx, p) in data.iter_mut().enumerate() {
+		let variance = (mean_squares[idx] - mean[idx] * mean[idx]).max(0.0);
+		let signal = (variance - noise).max(0.0);
+		let gain = signal / variance.max(noise).max(f32::EPSILON);
+
+		*p = mean[idx] + gain * (*p - mean[idx]);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::VstModel;
+
+	#[test]
+	fn anscombe_round_trip() {
+		let models = [
+			VstModel { a: 0.5, b: 4.0 },
+			VstModel { a: 2.0, b: 0.0 },
+			// Only Gaussian noise
+			VstModel { a: 0.0, b: 9.0 },
+		];
+
+		for vst in models {
+			for x in [0.0, 1.0, 10.0, 100.0, 1000.0] {
+				let back = vst.inverse(vst.forward(x));
+				assert!((back - x).abs() < x.max(1.0) * 1e-4, "{vst:?} {x} {back}");
+			}
+		}
+	}
+
+	#[test]
+	fn anscombe_stabilizes_variance() {
+		// The slope of the transform times the standard deviation is the
+		// standard deviation after it, which should be about one
+		let vst = VstModel { a: 0.5, b: 4.0 };
+		for x in [10.0, 100.0, 1000.0] {
+			let slope = (vst.forward(x + 0.01) - vst.forward(x - 0.01)) / 0.02;
+			let deviation = (vst.a * x + vst.b).sqrt();
+			assert!((slope * deviation - 1.0).abs() < 0.01, "{x}");
+		}
+	}
+}
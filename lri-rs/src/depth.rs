@@ -0,0 +1,425 @@
+//! Depth from the array of camera modules.
+//!
+//! Every other module is paired with the reference camera. We rectify the
+//! pair so the same point is on the same row of both images, find how far it
+//! moved along that row by block matching with zero-mean normalized
+//! cross-correlation, and turn that disparity into depth. The depths from all
+//! the pairs are fused with a median.
+//!
+//! It's done on gray images binned to half resolution, see [View]. We take the
+//! intrinsics to be in pixels on the sensor, in the order the image is stored
+//! in the file, like the distortion models. Depth is in the units of the
+//! calibrated translations, which we think are millimeters.
+
+use crate::{
+	geometry::{cross, dot, invert, mat_mul, mat_vec, normalize, project, sub, transpose},
+	image::box_mean,
+	CameraId, Extrinsics, FloatImage, LriFile, ModuleCalibration, MosaicOptions, Pose, RawImage,
+};
+
+/// An image from one module and where it was taken from
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+	/// One channel, or three from [View::from_image_rgb]
+	pub image: FloatImage,
+	/// The camera matrix for `image`, row-major
+	pub intrinsics: [f32; 9],
+	pub pose: Pose,
+	/// `image` is upside down from how the module's mosaic is stored, see
+	/// [View::from_image]
+	pub flipped: bool,
+}
+
+impl View {
+	/// Bin the module's [linear mosaic](RawImage::linear_mosaic) to a half
+	/// resolution gray image and move the intrinsics to match.
+	///
+	/// Images from behind a mirror with
+	/// [flip_img_around_x](crate::MirrorSystem::flip_img_around_x) set are
+	/// flipped upside down to go with their pose. We assume their intrinsics
+	/// are for the image before that.
+	///
+	/// Returns `None` if we can't get a
+	/// [linear mosaic](RawImage::linear_mosaic) of the image or the
+	/// calibration doesn't have intrinsics and extrinsics for it.
+	pub fn from_image(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		options: &MosaicOptions,
+	) -> Option<Self> {
+		Self::binned(image, calibration, options, false)
+	}
+
+	/// Like [View::from_image] but in color, see [FloatImage::binned_rgb]
+	pub fn from_image_rgb(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		options: &MosaicOptions,
+	) -> Option<Self> {
+		Self::binned(image, calibration, options, true)
+	}
+
+	fn binned(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		options: &MosaicOptions,
+		rgb: bool,
+	) -> Option<Self> {
+		let mosaic = image.linear_mosaic(Some(calibration), options)?;
+		Self::from_mosaic(image, calibration, &mosaic, rgb)
+	}
+
+	/// Like [View::from_image] with the image's linear mosaic already made
+	pub(crate) fn from_mosaic(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		mosaic: &[f32],
+		rgb: bool,
+	) -> Option<Self> {
+		let focus = calibration.focus_calibration(&image.capture)?;
+		let extrinsics = focus.extrinsics.as_ref()?;
+		let pose = extrinsics.pose(image.capture.mirror_position)?;
+
+		let mut binned = if rgb {
+			let cfa = image.stored_cfa_string();
+			FloatImage::binned_rgb(mosaic, image.width, image.height, cfa)
+		} else {
+			FloatImage::binned(mosaic, image.width, image.height)
+		};
+
+		// From sensor pixels to binned pixels, whose centers are in the middle
+		// of a 2x2 cell.
+		let (start_x, start_y) = (image.start.0 as f32, image.start.1 as f32);
+		#[rustfmt::skip]
+		let to_binned = [
+			0.5, 0.0, -(start_x + 0.5) / 2.0,
+			0.0, 0.5, -(start_y + 0.5) / 2.0,
+			0.0, 0.0, 1.0,
+		];
+		let mut intrinsics = mat_mul(&to_binned, &focus.intrinsics?.k);
+
+		let flip = matches!(
+			extrinsics,
+			Extrinsics::MovableMirror {
+				mirror_system: Some(ms),
+				..
+			} if ms.flip_img_around_x
+		);
+
+		if flip {
+			let (width, height, channels) = (binned.width, binned.height, binned.channels);
+			let rows: Vec<&[f32]> = binned.data.chunks_exact(width * channels).rev().collect();
+			binned = FloatImage::from_data(width, height, channels, rows.concat());
+
+			intrinsics[1] = -intrinsics[1];
+			intrinsics[3] = -intrinsics[3];
+			intrinsics[5] = (height - 1) as f32 - intrinsics[5];
+		}
+
+		Some(Self {
+			image: binned,
+			intrinsics,
+			pose,
+			flipped: flip,
+		})
+	}
+}
+
+/// How to go about making a depth map
+#[derive(Clone, Debug, PartialEq)]
+pub struct DepthOptions {
+	/// The modules to pair with the reference camera. `None` is every module
+	/// with the same focal length as it.
+	pub cameras: Option<Vec<CameraId>>,
+	/// The furthest to look for a match, in pixels of the half resolution
+	/// images. The closest depth we can see is the focal length times the
+	/// baseline over this.
+	pub max_disparity: usize,
+	/// Matching is done on windows `2 * window_radius + 1` pixels square
+	pub window_radius: usize,
+	/// How well a match has to correlate for us to believe it, up to 1.0
+	pub min_correlation: f32,
+	/// What to do to each module's mosaic before it's binned
+	pub mosaic: MosaicOptions,
+}
+
+impl Default for DepthOptions {
+	fn default() -> Self {
+		Self {
+			cameras: None,
+			max_disparity: 64,
+			window_radius: 3,
+			min_correlation: 0.6,
+			mosaic: MosaicOptions::default(),
+		}
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// A depth map of the scene aligned to the [reference image](LriFile::reference_image)
+	/// at half its resolution, so every pixel covers a 2x2 cell of it. Pixels
+	/// we couldn't find a depth for are 0.0.
+	///
+	/// Returns `None` if there's no reference image, or no other module to
+	/// pair it with, that we have a [View] of.
+	pub fn depth_map(&self, options: &DepthOptions) -> Option<FloatImage> {
+		let reference = self.reference_image()?;
+		let view =
+			|img: &RawImage| View::from_image(img, self.calibration(img.camera)?, &options.mosaic);
+
+		let others: Vec<View> = self
+			.images()
+			.filter(|img| img.camera != reference.camera)
+			.filter(|img| match &options.cameras {
+				Some(cameras) => cameras.contains(&img.camera),
+				None => img.camera.group() == reference.camera.group(),
+			})
+			.filter_map(view)
+			.collect();
+
+		if others.is_empty() {
+			return None;
+		}
+
+		Some(depth_map(&view(reference)?, &others, options))
+	}
+}
+
+/// Fuse the depth of every pair of `reference` and one of `others` into a
+/// depth map aligned to `reference`, taking the median where more than one
+/// pair found a depth. Pixels none did are 0.0.
+pub fn depth_map(reference: &View, others: &[View], options: &DepthOptions) -> FloatImage {
+	let (width, height) = (reference.image.width, reference.image.height);
+	let pairs: Vec<Vec<f32>> = others
+		.iter()
+		.filter_map(|other| pair_depth(reference, other, options))
+		.collect();
+
+	let mut out = FloatImage::new(width, height, 1);
+	let mut depths = Vec::with_capacity(pairs.len());
+	for (idx, px) in out.data.iter_mut().enumerate() {
+		depths.clear();
+		depths.extend(pairs.iter().map(|d| d[idx]).filter(|d| d.is_finite()));
+
+		if !depths.is_empty() {
+			depths.sort_by(f32::total_cmp);
+			let mid = depths.len() / 2;
+			*px = if depths.len() % 2 == 0 {
+				(depths[mid - 1] + depths[mid]) / 2.0
+			} else {
+				depths[mid]
+			};
+		}
+	}
+
+	out
+}
+
+/// Depth for every pixel of `reference` from matching it against `other`,
+/// NaN where there wasn't a good match. `None` if the two are in the same
+/// place or a camera matrix can't be inverted.
+fn pair_depth(reference: &View, other: &View, options: &DepthOptions) -> Option<Vec<f32>> {
+	let (width, height) = (reference.image.width, reference.image.height);
+
+	let baseline = sub(other.pose.center(), reference.pose.center());
+	let baseline_length = dot(baseline, baseline).sqrt();
+	if baseline_length < f32::EPSILON {
+		return None;
+	}
+
+	// The rectified cameras look about where the reference does with their x
+	// axis along the baseline (Fusiello, Trucco, and Verri).
+	let optical_axis = [
+		reference.pose.rotation[6],
+		reference.pose.rotation[7],
+		reference.pose.rotation[8],
+	];
+	let x = normalize(baseline);
+	let y = normalize(cross(optical_axis, x));
+	let z = cross(x, y);
+	let rectified = [x[0], x[1], x[2], y[0], y[1], y[2], z[0], z[1], z[2]];
+
+	let mut intrinsics = reference.intrinsics;
+	intrinsics[1] = 0.0;
+	let inverse_intrinsics = invert(&intrinsics)?;
+
+	// From the rectified images back to the ones we have
+	let to_view = |view: &View| {
+		let rotation = mat_mul(&view.pose.rotation, &transpose(&rectified));
+		mat_mul(&mat_mul(&view.intrinsics, &rotation), &inverse_intrinsics)
+	};
+	let left = warp(&reference.image, &to_view(reference), width, height);
+	let right = warp(&other.image, &to_view(other), width, height);
+
+	let disparity = disparity(&left, &right, width, height, options);
+
+	// Back from the reference image to the rectified one, and from rays in the
+	// rectified camera to the reference camera.
+	let from_rectified = mat_mul(&reference.pose.rotation, &transpose(&rectified));
+	let to_rectified = invert(&to_view(reference))?;
+	let focal_length = intrinsics[0];
+
+	let mut depth = vec![f32::NAN; width * height];
+	for y in 0..height {
+		for x in 0..width {
+			let (rx, ry) = project(&to_rectified, x as f32, y as f32);
+			let (rx, ry) = (rx.round(), ry.round());
+			if !(rx >= 0.0 && ry >= 0.0 && rx < width as f32 && ry < height as f32) {
+				continue;
+			}
+
+			let d = disparity[ry as usize * width + rx as usize];
+			if !d.is_finite() || d <= 0.0 {
+				continue;
+			}
+
+			let rectified_depth = focal_length * baseline_length / d;
+			let ray = mat_vec(&inverse_intrinsics, [rx, ry, 1.0]);
+			let point = mat_vec(&from_rectified, ray.map(|v| v * rectified_depth));
+
+			if point[2] > 0.0 {
+				depth[y * width + x] = point[2];
+			}
+		}
+	}
+
+	Some(depth)
+}
+
+/// A rectified gray image. `mask` is 1.0 where `values` came from inside the
+/// source image and 0.0 elsewhere.
+struct Warped {
+	values: Vec<f32>,
+	mask: Vec<f32>,
+}
+
+/// Resample `image` to `width` by `height` with `homography` taking every
+/// pixel of the output to where it is in `image`
+fn warp(image: &FloatImage, homography: &[f32; 9], width: usize, height: usize) -> Warped {
+	let mut values = vec![0.0; width * height];
+	let mut mask = vec![0.0; width * height];
+
+	for y in 0..height {
+		for x in 0..width {
+			let (sx, sy) = project(homography, x as f32, y as f32);
+			if let Some(v) = image.sample(sx, sy, 0) {
+				values[y * width + x] = v;
+				mask[y * width + x] = 1.0;
+			}
+		}
+	}
+
+	Warped { values, mask }
+}
+
+/// Disparity of every pixel of `left` to where it is in `right`, which is to
+/// its left, with subpixel precision. NaN where there was no good match, the
+/// best one was at either end of the range we looked in, or the pixel it
+/// matched in `right` matches a different one in `left`.
+fn disparity(
+	left: &Warped,
+	right: &Warped,
+	width: usize,
+	height: usize,
+	options: &DepthOptions,
+) -> Vec<f32> {
+	// Windows with less contrast than this can't be matched
+	const MIN_VARIANCE: f32 = 1e-5;
+
+	let radius = options.window_radius;
+	let count = width * height;
+	let squares = |data: &[f32]| data.iter().map(|v| v * v).collect::<Vec<f32>>();
+
+	let left_mean = box_mean(&left.values, width, height, radius);
+	let left_squares = box_mean(&squares(&left.values), width, height, radius);
+	let right_mean = box_mean(&right.values, width, height, radius);
+	let right_squares = box_mean(&squares(&right.values), width, height, radius);
+
+	// Only the best correlation and those on either side of it are kept
+	let mut best = vec![(0usize, f32::NEG_INFINITY); count];
+	let mut before = vec![f32::NAN; count];
+	let mut after = vec![f32::NAN; count];
+	let mut previous = vec![f32::NAN; count];
+	// The best match of every pixel of `right` too, to check they agree
+	let mut best_right = vec![(0usize, f32::NEG_INFINITY); count];
+
+	let mut products = vec![0.0; count];
+	let mut valid = vec![0.0; count];
+	for d in 0..=options.max_disparity {
+		products.iter_mut().for_each(|p| *p = 0.0);
+		valid.iter_mut().for_each(|p| *p = 0.0);
+
+		for y in 0..height {
+			for x in d..width {
+				let (l, r) = (y * width + x, y * width + x - d);
+				products[l] = left.values[l] * right.values[r];
+				valid[l] = left.mask[l] * right.mask[r];
+			}
+		}
+
+		let products = box_mean(&products, width, height, radius);
+		let valid = box_mean(&valid, width, height, radius);
+
+		for y in radius..height.saturating_sub(radius) {
+			for x in (d + radius)..width.saturating_sub(radius) {
+				let (l, r) = (y * width + x, y * width + x - d);
+
+				let left_variance = left_squares[l] - left_mean[l] * left_mean[l];
+				let right_variance = right_squares[r] - right_mean[r] * right_mean[r];
+
+				let score = if valid[l] < 0.999
+					|| left_variance < MIN_VARIANCE
+					|| right_variance < MIN_VARIANCE
+				{
+					f32::NAN
+				} else {
+					(products[l] - left_mean[l] * right_mean[r])
+						/ (left_variance * right_variance).sqrt()
+				};
+
+				if best[l].0 + 1 == d {
+					after[l] = score;
+				}
+
+				if score > best[l].1 {
+					best[l] = (d, score);
+					before[l] = previous[l];
+					after[l] = f32::NAN;
+				}
+
+				if score > best_right[r].1 {
+					best_right[r] = (d, score);
+				}
+
+				previous[l] = score;
+			}
+		}
+	}
+
+	best.iter()
+		.zip(before.iter().zip(after.iter()))
+		.enumerate()
+		.map(|(idx, (&(d, score), (&before, &after)))| {
+			if score < options.min_correlation || !before.is_finite() || !after.is_finite() {
+				return f32::NAN;
+			}
+
+			// Pixels that can't be seen from the right, like those off its
+			// edge, match something they shouldn't. What they match matches
+			// something else.
+			if best_right[idx - d].0.abs_diff(d) > 1 {
+				return f32::NAN;
+			}
+
+			// Fit a parabola through the three scores for where the peak is
+			let curvature = before - 2.0 * score + after;
+			let offset = if curvature < 0.0 {
+				((before - after) / (2.0 * curvature)).clamp(-0.5, 0.5)
+			} else {
+				0.0
+			};
+
+			d as f32 + offset
+		})
+		.collect()
+}
@@ -0,0 +1,302 @@
+//! Lens distortion from the factory geometric calibration.
+//!
+//! There are two models. The polynomial one is a radial polynomial around a
+//! distortion center and is what [undistort] and [undistort_mosaic] use. The
+//! CRA (chief ray angle) model is read, but we don't know the units of its
+//! tables yet so nothing uses it.
+//!
+//! All coordinates are in pixels on the sensor. An image that doesn't start
+//! at the top-left of the sensor, see [RawImage::start], has to be moved by
+//! where it does start, which [RawImage::undistort_mosaic] does for you.
+
+use lri_proto::{
+	distortion::{
+		distortion::{Polynomial, CRA},
+		Distortion as PbDistortion,
+	},
+	point2f::Point2F,
+	rectanglei::RectangleI,
+};
+
+use crate::{image::sample_bilinear, FloatImage, ModuleCalibration, RawImage};
+
+/// Distortion of a camera module. Either model could be missing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Distortion {
+	pub polynomial: Option<PolynomialDistortion>,
+	pub cra: Option<CraDistortion>,
+}
+
+impl From<PbDistortion> for Distortion {
+	fn from(dist: PbDistortion) -> Self {
+		Self {
+			polynomial: dist.polynomial.as_ref().map(<_>::into),
+			cra: dist.cra.as_ref().map(<_>::into),
+		}
+	}
+}
+
+/// Radial distortion around `center`.
+///
+/// A point is normalized by subtracting the center and dividing by
+/// `normalization`. With `r` being its distance from the center after that,
+/// it's moved away from the center by a factor of
+/// `1 + coeffs[0] * r^2 + coeffs[1] * r^4 + ...`, which is where it ends up in
+/// the image the sensor saw.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolynomialDistortion {
+	pub center: (f32, f32),
+	pub normalization: (f32, f32),
+	pub coeffs: Vec<f32>,
+	/// How well the polynomial fit the measurements, lower is better
+	pub fit_cost: Option<f32>,
+	/// The part of the sensor the model is good for
+	pub valid_roi: Option<Rect>,
+}
+
+impl PolynomialDistortion {
+	/// Where the point `x`, `y` in an undistorted image is in the distorted one
+	pub fn distort(&self, x: f32, y: f32) -> (f32, f32) {
+		let u = (x - self.center.0) / self.normalization.0;
+		let v = (y - self.center.1) / self.normalization.1;
+		let r2 = u * u + v * v;
+
+		let mut factor = 1.0;
+		let mut power = 1.0;
+		for k in &self.coeffs {
+			power *= r2;
+			factor += k * power;
+		}
+
+		(
+			self.center.0 + u * factor * self.normalization.0,
+			self.center.1 + v * factor * self.normalization.1,
+		)
+	}
+
+	/// Whether the model is good at `x`, `y`. Everywhere is if there's no
+	/// valid ROI.
+	pub fn is_valid(&self, x: f32, y: f32) -> bool {
+		self.valid_roi.map(|roi| roi.contains(x, y)).unwrap_or(true)
+	}
+}
+
+impl From<&Polynomial> for PolynomialDistortion {
+	fn from(poly: &Polynomial) -> Self {
+		Self {
+			center: point(poly.distortion_center.get_or_default()),
+			normalization: point(poly.normalization.get_or_default()),
+			coeffs: poly.coeffs.clone(),
+			fit_cost: poly.fit_cost,
+			valid_roi: poly.valid_roi.as_ref().map(<_>::into),
+		}
+	}
+}
+
+/// Distortion from the angle light hits the sensor at. We don't know what
+/// the pairs in `cra` and `coeffs` are yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CraDistortion {
+	pub center: (f32, f32),
+	pub sensor_distance: f32,
+	pub exit_pupil_distance: f32,
+	pub pixel_size: f32,
+	pub cra: Vec<(f32, f32)>,
+	pub coeffs: Vec<(f32, f32)>,
+	pub fit_cost: Option<f32>,
+	pub valid_roi: Option<Rect>,
+	/// Where the lens was, as a hall sensor code, when this was measured
+	pub lens_position: Option<f32>,
+	pub distance_hall_ratio: Option<f32>,
+}
+
+impl From<&CRA> for CraDistortion {
+	fn from(cra: &CRA) -> Self {
+		Self {
+			center: point(cra.distortion_center.get_or_default()),
+			sensor_distance: cra.sensor_distance(),
+			exit_pupil_distance: cra.exit_pupil_distance(),
+			pixel_size: cra.pixel_size(),
+			cra: cra.cra.iter().map(point).collect(),
+			coeffs: cra.coeffs.iter().map(point).collect(),
+			fit_cost: cra.fit_cost,
+			valid_roi: cra.valid_roi.as_ref().map(<_>::into),
+			lens_position: cra.lens_hall_code,
+			distance_hall_ratio: cra.distance_hall_ratio,
+		}
+	}
+}
+
+/// A rectangle of pixels
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
+	pub x: i32,
+	pub y: i32,
+	pub width: i32,
+	pub height: i32,
+}
+
+impl Rect {
+	pub fn contains(&self, x: f32, y: f32) -> bool {
+		// These come straight from the file, so adding them as i32 can overflow
+		let right = self.x as i64 + self.width as i64;
+		let bottom = self.y as i64 + self.height as i64;
+
+		x >= self.x as f32 && y >= self.y as f32 && x < right as f32 && y < bottom as f32
+	}
+}
+
+impl From<&RectangleI> for Rect {
+	fn from(rect: &RectangleI) -> Self {
+		Self {
+			x: rect.x(),
+			y: rect.y(),
+			width: rect.width(),
+			height: rect.height(),
+		}
+	}
+}
+
+fn point(p: &Point2F) -> (f32, f32) {
+	(p.x(), p.y())
+}
+
+impl<'img> RawImage<'img> {
+	/// Undistort a linear mosaic in the order it's stored in the file, like what
+	/// [RawImage::denoise] gives you, with the polynomial model.
+	///
+	/// Returns `None` if `calibration` doesn't have a polynomial model.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn undistort_mosaic(
+		&self,
+		calibration: &ModuleCalibration,
+		mosaic: &[f32],
+	) -> Option<Vec<f32>> {
+		let model = calibration.distortion.as_ref()?.polynomial.as_ref()?;
+
+		Some(undistort_mosaic(
+			mosaic,
+			self.width,
+			self.height,
+			(self.start.0 as f32, self.start.1 as f32),
+			model,
+		))
+	}
+}
+
+/// Undistort an image. `offset` is where on the sensor the top-left of the
+/// image is. Pixels that come from outside the image or the model's valid ROI
+/// are zero.
+pub fn undistort(
+	image: &FloatImage,
+	offset: (f32, f32),
+	model: &PolynomialDistortion,
+) -> FloatImage {
+	let mut out = FloatImage::new(image.width, image.height, image.channels);
+
+	for y in 0..image.height {
+		for x in 0..image.width {
+			let Some((sx, sy)) = source(x, y, offset, model) else {
+				continue;
+			};
+
+			for c in 0..image.channels {
+				if let Some(v) = image.sample(sx, sy, c) {
+					out.set(x, y, c, v);
+				}
+			}
+		}
+	}
+
+	out
+}
+
+/// Undistort a 2x2 CFA mosaic without demosaicing it. Every pixel is
+/// interpolated from the pixels of the same colour around where it comes
+/// from, going by where they are in the mosaic, so the CFA only has to be
+/// laid out in 2x2 cells. `offset` is where on the sensor the top-left of the
+/// mosaic is. Pixels that come from outside the mosaic or the model's valid
+/// ROI are zero.
+///
+/// # Panics
+/// If `mosaic` isn't `width * height` long
+pub fn undistort_mosaic(
+	mosaic: &[f32],
+	width: usize,
+	height: usize,
+	offset: (f32, f32),
+	model: &PolynomialDistortion,
+) -> Vec<f32> {
+	assert_eq!(mosaic.len(), width * height);
+	let mut out = vec![0.0; mosaic.len()];
+
+	for y in 0..height {
+		for x in 0..width {
+			let Some((sx, sy)) = source(x, y, offset, model) else {
+				continue;
+			};
+
+			// Position in the plane of this pixel's colour
+			let (off_x, off_y) = (x % 2, y % 2);
+			let plane_x = (sx - off_x as f32) / 2.0;
+			let plane_y = (sy - off_y as f32) / 2.0;
+			let plane_width = (width + 1 - off_x) / 2;
+			let plane_height = (height + 1 - off_y) / 2;
+
+			let value = sample_bilinear(plane_x, plane_y, plane_width, plane_height, |px, py| {
+				mosaic[(py * 2 + off_y) * width + px * 2 + off_x]
+			});
+
+			if let Some(v) = value {
+				out[y * width + x] = v;
+			}
+		}
+	}
+
+	out
+}
+
+/// Where the undistorted pixel at `x`, `y` comes from, in image coordinates.
+/// `None` if it's outside the valid ROI.
+fn source(
+	x: usize,
+	y: usize,
+	offset: (f32, f32),
+	model: &PolynomialDistortion,
+) -> Option<(f32, f32)> {
+	let (sx, sy) = model.distort(x as f32 + offset.0, y as f32 + offset.1);
+	model
+		.is_valid(sx, sy)
+		.then_some((sx - offset.0, sy - offset.1))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn rect_contains() {
+		let rect = Rect {
+			x: 10,
+			y: 20,
+			width: 100,
+			height: 50,
+		};
+		assert!(rect.contains(10.0, 20.0));
+		assert!(rect.contains(109.5, 69.5));
+		assert!(!rect.contains(110.0, 30.0));
+		assert!(!rect.contains(9.5, 30.0));
+
+		// Garbage from a file mustn't overflow
+		let huge = Rect {
+			x: i32::MAX,
+			y: i32::MAX,
+			width: i32::MAX,
+			height: i32::MAX,
+		};
+		assert!(!huge.contains(0.0, 0.0));
+		assert!(huge.contains(i32::MAX as f32, i32::MAX as f32));
+	}
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use crate::CameraId;
+
+/// Everything that can go wrong while decoding an LRI.
+///
+/// Each variant carries the index of the block it happened in, counting from
+/// zero, and an `offset` which is a byte offset from the start of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LriError {
+	/// There weren't enough bytes left in the file for a block header.
+	TruncatedHeader { block: usize, offset: usize },
+	/// The block is longer than the data left in the file.
+	TruncatedBlock {
+		block: usize,
+		offset: usize,
+		missing: usize,
+	},
+	/// Something the block points to, like an image or the message, lies
+	/// outside of the block.
+	OutOfBounds {
+		block: usize,
+		offset: usize,
+		field: &'static str,
+		length: usize,
+	},
+	/// A block header did not start with the "LELR" magic number.
+	BadMagic {
+		block: usize,
+		offset: usize,
+		found: [u8; 4],
+	},
+	/// The block header's message type isn't one we know about.
+	UnknownBlockType {
+		block: usize,
+		offset: usize,
+		kind: u8,
+	},
+	/// The protobuf message in a block failed to parse. `message` is the name
+	/// of the message we expected, `reason` is what the protobuf crate said.
+	Message {
+		block: usize,
+		offset: usize,
+		message: &'static str,
+		reason: String,
+	},
+	/// A field we need to make sense of the file wasn't there.
+	MissingField {
+		block: usize,
+		offset: usize,
+		field: &'static str,
+	},
+	/// A field held a value we don't know how to interpret.
+	UnsupportedValue {
+		block: usize,
+		offset: usize,
+		field: &'static str,
+		value: i64,
+	},
+	/// The BayerJPEG header for an image ran past the end of the block.
+	TruncatedBayerJpeg {
+		block: usize,
+		offset: usize,
+		camera: CameraId,
+	},
+	/// A BayerJPEG did not start with the "BJPG" magic number.
+	BadBayerJpegMagic {
+		block: usize,
+		offset: usize,
+		camera: CameraId,
+		found: [u8; 4],
+	},
+}
+
+impl fmt::Display for LriError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::TruncatedHeader { block, offset } => {
+				write!(
+					f,
+					"block {block} at {offset:#x}: not enough data for a header"
+				)
+			}
+			Self::TruncatedBlock {
+				block,
+				offset,
+				missing,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: block is missing {missing} bytes"
+			),
+			Self::OutOfBounds {
+				block,
+				offset,
+				field,
+				length,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: {field} of {length} bytes runs past the end of the block"
+			),
+			Self::BadMagic {
+				block,
+				offset,
+				found,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: expected magic \"LELR\", found {found:?}"
+			),
+			Self::UnknownBlockType {
+				block,
+				offset,
+				kind,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: block type {kind} is unknown"
+			),
+			Self::Message {
+				block,
+				offset,
+				message,
+				reason,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: failed to parse {message}: {reason}"
+			),
+			Self::MissingField {
+				block,
+				offset,
+				field,
+			} => write!(f, "block {block} at {offset:#x}: missing field {field}"),
+			Self::UnsupportedValue {
+				block,
+				offset,
+				field,
+				value,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: unsupported value {value} in {field}"
+			),
+			Self::TruncatedBayerJpeg {
+				block,
+				offset,
+				camera,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: BayerJPEG data for {camera} is truncated"
+			),
+			Self::BadBayerJpegMagic {
+				block,
+				offset,
+				camera,
+				found,
+			} => write!(
+				f,
+				"block {block} at {offset:#x}: expected BayerJPEG magic \"BJPG\" for {camera}, found {found:?}"
+			),
+		}
+	}
+}
+
+impl std::error::Error for LriError {}
@@ -0,0 +1,337 @@
+//! Merging the modules that share a focal length into one image with less
+//! noise than any of them.
+//!
+//! Every module is registered to the reference camera with the homography its
+//! calibration gives for a plane at the distance the reference was focused at,
+//! then nudged by whichever translation lines it up best, to make up for
+//! parallax and calibration error. Modules are brought to the reference's
+//! exposure and averaged, weighted by how much signal they got. Samples far
+//! from the median of a pixel are left out so things that moved, or are at a
+//! different depth than the plane, don't ghost.
+//!
+//! It all happens at half resolution on the [color views](View::from_image_rgb)
+//! of the modules, so the result is a linear RGB image aligned to the
+//! reference image with every pixel covering a 2x2 cell of it.
+
+use crate::{
+	depth::View,
+	geometry::{invert, mat_mul, mat_vec, project, transpose},
+	CameraId, FloatImage, LriFile, ModuleCalibration, MosaicOptions, RawImage,
+};
+
+/// Values at or above this, before being scaled to the reference's exposure,
+/// are taken to be clipped
+pub(crate) const SATURATED: f32 = 0.95;
+
+/// A module's image ready to be merged
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+	/// Three channels
+	pub view: View,
+	/// Exposure in seconds times the total gain. How bright the module saw the
+	/// same light.
+	pub sensitivity: f32,
+}
+
+impl Frame {
+	/// Returns `None` if we can't get a [View] of the image, or it had no
+	/// exposure or gain.
+	pub fn from_image(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		options: &MosaicOptions,
+	) -> Option<Self> {
+		let mosaic = image.linear_mosaic(Some(calibration), options)?;
+		Self::from_mosaic(image, calibration, &mosaic)
+	}
+
+	/// Like [Frame::from_image] with the image's linear mosaic already made
+	pub(crate) fn from_mosaic(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		mosaic: &[f32],
+	) -> Option<Self> {
+		let sensitivity = image.capture.exposure.as_secs_f32() * image.capture.total_gain();
+		if sensitivity.is_nan() || sensitivity <= 0.0 {
+			return None;
+		}
+
+		Some(Self {
+			view: View::from_mosaic(image, calibration, mosaic, true)?,
+			sensitivity,
+		})
+	}
+}
+
+/// How to go about fusing modules
+#[derive(Clone, Debug, PartialEq)]
+pub struct FusionOptions {
+	/// The modules to merge with the reference camera. `None` is every module
+	/// with the same focal length as it.
+	pub cameras: Option<Vec<CameraId>>,
+	/// Distance to the plane modules are registered on, in the units of the
+	/// calibrated translations. `None` is the distance the reference camera
+	/// was focused at, or infinity if we don't know that.
+	pub plane_distance: Option<f32>,
+	/// The furthest to look when lining a module up, in pixels of the half
+	/// resolution images
+	pub search_radius: usize,
+	/// How far a sample can be from the median of a pixel and still be used,
+	/// relative to the median
+	pub tolerance: f32,
+	/// What to do to each module's mosaic before it's binned
+	pub mosaic: MosaicOptions,
+}
+
+impl Default for FusionOptions {
+	fn default() -> Self {
+		Self {
+			cameras: None,
+			plane_distance: None,
+			search_radius: 8,
+			tolerance: 0.1,
+			mosaic: MosaicOptions::default(),
+		}
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// Fuse the reference camera's group of modules, see [fusion](crate::fusion).
+	/// The result is in the units of the reference image's
+	/// [linear mosaic](RawImage::linear_mosaic), at its exposure.
+	///
+	/// Returns `None` if there's no reference image or we can't get a
+	/// [Frame] of it.
+	pub fn fuse(&self, options: &FusionOptions) -> Option<FloatImage> {
+		let reference = self.reference_image()?;
+		let frame =
+			|img: &RawImage| Frame::from_image(img, self.calibration(img.camera)?, &options.mosaic);
+
+		let others: Vec<Frame> = self
+			.images()
+			.filter(|img| img.camera != reference.camera)
+			.filter(|img| match &options.cameras {
+				Some(cameras) => cameras.contains(&img.camera),
+				None => img.camera.group() == reference.camera.group(),
+			})
+			.filter_map(frame)
+			.collect();
+
+		let plane_distance = options
+			.plane_distance
+			.or_else(|| self.focus_distance(reference))
+			.unwrap_or(f32::INFINITY);
+
+		Some(fuse(&frame(reference)?, &others, plane_distance, options))
+	}
+}
+
+/// Merge `others` into `reference`, registering them on a plane
+/// `plane_distance` in front of it. Pixels no frame saw without clipping are
+/// what the reference has there.
+pub fn fuse(
+	reference: &Frame,
+	others: &[Frame],
+	plane_distance: f32,
+	options: &FusionOptions,
+) -> FloatImage {
+	let image = &reference.view.image;
+	let (width, height, channels) = (image.width, image.height, image.channels);
+
+	let aligned: Vec<Aligned> = others
+		.iter()
+		.filter_map(|other| Aligned::new(reference, other, plane_distance, options.search_radius))
+		.collect();
+
+	let mut out = image.clone();
+	let mut samples: Vec<(Vec<f32>, f32)> = Vec::with_capacity(aligned.len() + 1);
+	let mut luminances = Vec::with_capacity(aligned.len() + 1);
+	for y in 0..height {
+		for x in 0..width {
+			samples.clear();
+
+			let own: Vec<f32> = (0..channels).map(|c| image.get(x, y, c)).collect();
+			if own.iter().all(|v| *v < SATURATED) {
+				samples.push((own, reference.sensitivity));
+			}
+			samples.extend(aligned.iter().filter_map(|a| a.sample(x as f32, y as f32)));
+
+			if samples.is_empty() {
+				continue;
+			}
+
+			luminances.clear();
+			luminances.extend(samples.iter().map(|(s, _)| luminance(s)));
+			luminances.sort_by(f32::total_cmp);
+			let median = luminances[luminances.len() / 2];
+			let allowed = median.abs() * options.tolerance + 1e-3;
+
+			let mut sum = vec![0.0; channels];
+			let mut total = 0.0;
+			for (sample, weight) in &samples {
+				if (luminance(sample) - median).abs() > allowed {
+					continue;
+				}
+
+				for (s, v) in sum.iter_mut().zip(sample) {
+					*s += v * weight;
+				}
+				total += weight;
+			}
+
+			for (c, s) in sum.into_iter().enumerate() {
+				out.set(x, y, c, s / total);
+			}
+		}
+	}
+
+	out
+}
+
+pub(crate) fn luminance(sample: &[f32]) -> f32 {
+	sample.iter().sum::<f32>() / sample.len() as f32
+}
+
+/// A frame and how to get to it from the reference image
+#[derive(Copy, Clone)]
+pub(crate) struct Aligned<'f> {
+	pub frame: &'f Frame,
+	/// From a pixel of the reference image to one of `frame`
+	pub homography: [f32; 9],
+	/// Added to a pixel of the reference image before the homography
+	pub shift: (f32, f32),
+	/// Brings `frame` to the reference's exposure
+	pub scale: f32,
+}
+
+impl<'f> Aligned<'f> {
+	/// `None` if a camera matrix can't be inverted or we couldn't line the
+	/// frame up
+	pub fn new(
+		reference: &Frame,
+		frame: &'f Frame,
+		plane_distance: f32,
+		search_radius: usize,
+	) -> Option<Self> {
+		let (rv, fv) = (&reference.view, &frame.view);
+
+		// A point on the plane, in reference camera coordinates, is moved to
+		// the frame's with `rotation + translation * [0, 0, 1 / distance]`
+		let rotation = mat_mul(&fv.pose.rotation, &transpose(&rv.pose.rotation));
+		let rotated = mat_vec(&rotation, rv.pose.translation);
+		let translation = [0, 1, 2].map(|i| fv.pose.translation[i] - rotated[i]);
+
+		let mut plane = rotation;
+		for row in 0..3 {
+			plane[row * 3 + 2] += translation[row] / plane_distance;
+		}
+
+		let homography = mat_mul(&mat_mul(&fv.intrinsics, &plane), &invert(&rv.intrinsics)?);
+
+		let mut aligned = Self {
+			frame,
+			homography,
+			shift: (0.0, 0.0),
+			scale: reference.sensitivity / frame.sensitivity,
+		};
+		aligned.shift = aligned.refine(&rv.image, search_radius)?;
+
+		Some(aligned)
+	}
+
+	/// The frame at a pixel of the reference image, at the reference's
+	/// exposure, and how much to weight it. `None` outside of the frame or
+	/// where it clipped.
+	pub fn sample(&self, x: f32, y: f32) -> Option<(Vec<f32>, f32)> {
+		let image = &self.frame.view.image;
+		let (sx, sy) = project(&self.homography, x + self.shift.0, y + self.shift.1);
+
+		let mut values = Vec::with_capacity(image.channels);
+		for c in 0..image.channels {
+			let v = image.sample(sx, sy, c)?;
+			if v >= SATURATED {
+				return None;
+			}
+
+			values.push(v * self.scale);
+		}
+
+		Some((values, self.frame.sensitivity))
+	}
+
+	/// The shift, to a fraction of a pixel, that best lines the frame up with
+	/// `reference`. Only every fourth pixel each way that the frame covers
+	/// before it's shifted is compared, to keep it quick. `None` if the frame
+	/// doesn't overlap the reference enough to tell.
+	fn refine(&self, reference: &FloatImage, radius: usize) -> Option<(f32, f32)> {
+		const STEP: usize = 4;
+
+		let points: Vec<(usize, usize, f32)> = (radius..reference.height.saturating_sub(radius))
+			.step_by(STEP)
+			.flat_map(|y| {
+				(radius..reference.width.saturating_sub(radius))
+					.step_by(STEP)
+					.map(move |x| (x, y))
+			})
+			.filter(|(x, y)| self.sample(*x as f32, *y as f32).is_some())
+			.filter_map(|(x, y)| {
+				let px: Vec<f32> = (0..reference.channels)
+					.map(|c| reference.get(x, y, c))
+					.collect();
+				px.iter()
+					.all(|v| *v < SATURATED)
+					.then(|| (x, y, luminance(&px)))
+			})
+			.collect();
+
+		let cost = |dx: f32, dy: f32| {
+			let mut error = 0.0;
+			let mut count = 0;
+			for (x, y, lum) in &points {
+				let shifted = Self {
+					shift: (dx, dy),
+					..*self
+				};
+
+				if let Some((sample, _)) = shifted.sample(*x as f32, *y as f32) {
+					let diff = luminance(&sample) - lum;
+					error += diff * diff;
+					count += 1;
+				}
+			}
+
+			(count * 2 >= points.len() && count > 0).then(|| error / count as f32)
+		};
+
+		let radius = radius as i32;
+		let mut costs = vec![];
+		for dy in -radius..=radius {
+			for dx in -radius..=radius {
+				if let Some(c) = cost(dx as f32, dy as f32) {
+					costs.push(((dx, dy), c));
+				}
+			}
+		}
+
+		let &((dx, dy), best) = costs.iter().min_by(|a, b| a.1.total_cmp(&b.1))?;
+		let at = |x: i32, y: i32| {
+			costs
+				.iter()
+				.find(|(shift, _)| *shift == (x, y))
+				.map(|(_, c)| *c)
+		};
+
+		// Fit a parabola each way for where the bottom is
+		let offset = |before: Option<f32>, after: Option<f32>| match (before, after) {
+			(Some(before), Some(after)) if before + after > 2.0 * best => {
+				((before - after) / (2.0 * (before - 2.0 * best + after))).clamp(-0.5, 0.5)
+			}
+			_ => 0.0,
+		};
+
+		Some((
+			dx as f32 + offset(at(dx - 1, dy), at(dx + 1, dy)),
+			dy as f32 + offset(at(dx, dy - 1), at(dx, dy + 1)),
+		))
+	}
+}
@@ -0,0 +1,439 @@
+//! Where the camera modules are and how they see, from the factory geometric
+//! calibration.
+//!
+//! Each module was calibrated at a few focus distances. [Geometry::at_lens_position]
+//! interpolates between them for wherever the lens actually was.
+//!
+//! Matrices are row-major `[f32; 9]` like everywhere else in lri-rs.
+
+use lri_proto::{
+	geometric_calibration::{
+		geometric_calibration::{
+			CalibrationFocusBundle, Extrinsics as PbExtrinsics, MirrorType as PbMirrorType,
+		},
+		GeometricCalibration,
+	},
+	matrix3x3f::Matrix3x3F,
+	point3f::Point3F,
+	range2f::Range2F,
+};
+
+use crate::{
+	LriFile, MirrorActuatorMapping, MirrorSystem, ModuleCalibration, ModuleCapture, RawImage,
+};
+
+/// The geometric calibration of a camera module
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geometry {
+	pub mirror_type: MirrorType,
+	/// Calibration at each focus distance that was measured
+	pub per_focus: Vec<FocusCalibration>,
+	/// The hall codes the lens can be at
+	pub lens_position_range: Option<(f32, f32)>,
+	/// The distances the lens can focus at
+	pub focus_distance_range: Option<(f32, f32)>,
+}
+
+impl Geometry {
+	/// The calibration for the lens at `lens_position`, a hall sensor code,
+	/// linearly interpolated between the two focus distances around it.
+	/// Positions outside the ones calibrated get the nearest one.
+	///
+	/// If every focus distance has the hall code it was measured at we use
+	/// that. If not, we go by the lens position and focus distance ranges and
+	/// assume the lens moves linearly with the inverse of the distance it's
+	/// focused at, and that the low ends of both ranges go together.
+	///
+	/// Returns `None` if there's nothing calibrated or we can't tell which
+	/// focus distance goes with `lens_position`.
+	pub fn at_lens_position(&self, lens_position: f32) -> Option<FocusCalibration> {
+		let (keys, target): (Vec<f32>, f32) =
+			if let Some(positions) = self.per_focus.iter().map(|fc| fc.lens_position).collect() {
+				(positions, lens_position)
+			} else if self.per_focus.len() == 1 {
+				return self.per_focus.first().cloned();
+			} else {
+				let (lens_min, lens_max) = self.lens_position_range?;
+				let (focus_min, focus_max) = self.focus_distance_range?;
+
+				let t = (lens_position - lens_min) / (lens_max - lens_min);
+				let diopters = lerp(1.0 / focus_min, 1.0 / focus_max, t);
+				let keys = self
+					.per_focus
+					.iter()
+					.map(|fc| 1.0 / fc.focus_distance)
+					.collect();
+
+				(keys, diopters)
+			};
+
+		let mut order: Vec<usize> = (0..keys.len()).collect();
+		order.sort_by(|a, b| keys[*a].total_cmp(&keys[*b]));
+
+		let first = *order.first()?;
+		let last = *order.last()?;
+		if target <= keys[first] {
+			return Some(self.per_focus[first].clone());
+		}
+		if target >= keys[last] {
+			return Some(self.per_focus[last].clone());
+		}
+
+		let (a, b) = order
+			.windows(2)
+			.map(|pair| (pair[0], pair[1]))
+			.find(|(_, b)| keys[*b] >= target)?;
+
+		let t = (target - keys[a]) / (keys[b] - keys[a]);
+		Some(self.per_focus[a].interpolate(&self.per_focus[b], t))
+	}
+}
+
+impl From<&GeometricCalibration> for Geometry {
+	fn from(gc: &GeometricCalibration) -> Self {
+		Self {
+			mirror_type: gc.mirror_type().into(),
+			per_focus: gc.per_focus_calibration.iter().map(<_>::into).collect(),
+			lens_position_range: gc.lens_hall_code_range.as_ref().map(range),
+			focus_distance_range: gc.focus_distance_range.as_ref().map(range),
+		}
+	}
+}
+
+/// What sort of mirror, if any, a module looks through
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MirrorType {
+	None,
+	/// It's there, but it doesn't move
+	Glued,
+	Movable,
+}
+
+impl From<PbMirrorType> for MirrorType {
+	fn from(mt: PbMirrorType) -> Self {
+		match mt {
+			PbMirrorType::NONE => Self::None,
+			PbMirrorType::GLUED => Self::Glued,
+			PbMirrorType::MOVABLE => Self::Movable,
+		}
+	}
+}
+
+/// Calibration of a module focused at one distance
+#[derive(Clone, Debug, PartialEq)]
+pub struct FocusCalibration {
+	pub focus_distance: f32,
+	/// Where the lens was, as a hall sensor code
+	pub lens_position: Option<f32>,
+	pub intrinsics: Option<Intrinsics>,
+	pub extrinsics: Option<Extrinsics>,
+	/// Temperature of the sensor, probably in °C
+	pub sensor_temperature: Option<i32>,
+}
+
+impl FocusCalibration {
+	/// Blend with `other`, `t` being how far toward it to go. Things we can't
+	/// blend, like a movable mirror or something only one of them has, come
+	/// from whichever is closer.
+	pub fn interpolate(&self, other: &Self, t: f32) -> Self {
+		let nearest = if t < 0.5 { self } else { other };
+
+		let intrinsics = match (&self.intrinsics, &other.intrinsics) {
+			(Some(a), Some(b)) => Some(Intrinsics {
+				k: lerp_array(a.k, b.k, t),
+				rms_error: nearest.intrinsics.as_ref().and_then(|i| i.rms_error),
+			}),
+			_ => nearest.intrinsics,
+		};
+
+		let extrinsics = match (&self.extrinsics, &other.extrinsics) {
+			(Some(Extrinsics::Canonical(a)), Some(Extrinsics::Canonical(b))) => {
+				Some(Extrinsics::Canonical(CanonicalExtrinsics {
+					pose: a.pose.interpolate(&b.pose, t),
+					..*if t < 0.5 { a } else { b }
+				}))
+			}
+			_ => nearest.extrinsics.clone(),
+		};
+
+		Self {
+			focus_distance: lerp(self.focus_distance, other.focus_distance, t),
+			lens_position: self
+				.lens_position
+				.zip(other.lens_position)
+				.map(|(a, b)| lerp(a, b, t)),
+			intrinsics,
+			extrinsics,
+			sensor_temperature: nearest.sensor_temperature,
+		}
+	}
+}
+
+impl From<&CalibrationFocusBundle> for FocusCalibration {
+	fn from(cfb: &CalibrationFocusBundle) -> Self {
+		Self {
+			focus_distance: cfb.focus_distance(),
+			lens_position: cfb.focus_hall_code,
+			intrinsics: cfb.intrinsics.as_ref().map(|i| Intrinsics {
+				k: matrix3x3(i.k_mat.get_or_default()),
+				rms_error: i.rms_error,
+			}),
+			extrinsics: cfb.extrinsics.as_ref().and_then(Extrinsics::from_proto),
+			sensor_temperature: cfb.sensor_temp,
+		}
+	}
+}
+
+/// The camera matrix
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Intrinsics {
+	/// `[fx, s, cx, 0, fy, cy, 0, 0, 1]`, in pixels
+	pub k: [f32; 9],
+	pub rms_error: Option<f32>,
+}
+
+impl Intrinsics {
+	/// Focal length in pixels, x and y
+	pub fn focal_length(&self) -> (f32, f32) {
+		(self.k[0], self.k[4])
+	}
+
+	/// Where the optical axis hits the sensor, in pixels
+	pub fn principal_point(&self) -> (f32, f32) {
+		(self.k[2], self.k[5])
+	}
+}
+
+/// Where a module is and which way it's looking
+#[derive(Clone, Debug, PartialEq)]
+pub enum Extrinsics {
+	/// A fixed pose
+	Canonical(CanonicalExtrinsics),
+	/// The pose depends on where the mirror is
+	MovableMirror {
+		mirror_system: Option<MirrorSystem>,
+		actuator: Option<MirrorActuatorMapping>,
+	},
+}
+
+impl Extrinsics {
+	/// The pose of the module with its mirror at `mirror_position`, a hall
+	/// sensor code, which only matters if it has a movable one. See
+	/// [MirrorSystem::virtual_pose].
+	pub fn pose(&self, mirror_position: i32) -> Option<Pose> {
+		match self {
+			Self::Canonical(canon) => Some(canon.pose),
+			Self::MovableMirror {
+				mirror_system,
+				actuator,
+			} => {
+				let angle = actuator.as_ref()?.angle(mirror_position)?;
+				Some(mirror_system.as_ref()?.virtual_pose(angle))
+			}
+		}
+	}
+
+	fn from_proto(ext: &PbExtrinsics) -> Option<Self> {
+		if let Some(canon) = ext.canonical.as_ref() {
+			return Some(Self::Canonical(CanonicalExtrinsics {
+				pose: Pose {
+					rotation: matrix3x3(canon.rotation.get_or_default()),
+					translation: vector3(canon.translation.get_or_default()),
+				},
+				stereo_error: canon.stereo_error,
+				reprojection_error: canon.reprojection_error,
+			}));
+		}
+
+		ext.moveable_mirror.as_ref().map(|mm| Self::MovableMirror {
+			mirror_system: mm.mirror_system.as_ref().map(<_>::into),
+			actuator: mm.mirror_actuator_mapping.as_ref().map(<_>::into),
+		})
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CanonicalExtrinsics {
+	pub pose: Pose,
+	pub stereo_error: Option<f32>,
+	pub reprojection_error: Option<f32>,
+}
+
+/// A rigid transform from world to camera coordinates. A point `p` in the
+/// world is at `rotation * p + translation` to the camera. We don't know
+/// what the world is yet, but it's the same for every module in a file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pose {
+	/// Row-major
+	pub rotation: [f32; 9],
+	pub translation: [f32; 3],
+}
+
+impl Pose {
+	pub const IDENTITY: Self = Self {
+		rotation: IDENTITY,
+		translation: [0.0; 3],
+	};
+
+	/// Move a point from world to camera coordinates
+	pub fn transform(&self, point: [f32; 3]) -> [f32; 3] {
+		add(mat_vec(&self.rotation, point), self.translation)
+	}
+
+	/// Where the camera is in world coordinates
+	pub fn center(&self) -> [f32; 3] {
+		scale(mat_vec(&transpose(&self.rotation), self.translation), -1.0)
+	}
+
+	/// The pose that undoes this one, camera to world
+	pub fn inverse(&self) -> Self {
+		let rotation = transpose(&self.rotation);
+		Self {
+			rotation,
+			translation: scale(mat_vec(&rotation, self.translation), -1.0),
+		}
+	}
+
+	/// Blend toward `other`. The rotation is blended linearly and made a
+	/// rotation again, which is fine for the small differences between focus
+	/// distances.
+	pub fn interpolate(&self, other: &Self, t: f32) -> Self {
+		Self {
+			rotation: orthonormalize(&lerp_array(self.rotation, other.rotation, t)),
+			translation: lerp_array(self.translation, other.translation, t),
+		}
+	}
+}
+
+impl ModuleCalibration {
+	/// The geometry of this module for an image it took, interpolated to
+	/// where its lens was. See [Geometry::at_lens_position].
+	pub fn focus_calibration(&self, capture: &ModuleCapture) -> Option<FocusCalibration> {
+		self.geometry
+			.as_ref()?
+			.at_lens_position(capture.lens_position as f32)
+	}
+
+	/// Where this module was and which way it was looking for an image it
+	/// took, with its lens and mirror where they were.
+	pub fn pose(&self, capture: &ModuleCapture) -> Option<Pose> {
+		self.focus_calibration(capture)?
+			.extrinsics?
+			.pose(capture.mirror_position)
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// The distance the module that took `image` was focused at, from its
+	/// calibration
+	pub fn focus_distance(&self, image: &RawImage) -> Option<f32> {
+		self.calibration(image.camera)?
+			.focus_calibration(&image.capture)
+			.map(|focus| focus.focus_distance)
+	}
+}
+
+pub(crate) const IDENTITY: [f32; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+
+pub(crate) fn matrix3x3(mat: &Matrix3x3F) -> [f32; 9] {
+	#[rustfmt::skip]
+	let mat = [
+		mat.x00(), mat.x01(), mat.x02(),
+		mat.x10(), mat.x11(), mat.x12(),
+		mat.x20(), mat.x21(), mat.x22(),
+	];
+	mat
+}
+
+pub(crate) fn vector3(p: &Point3F) -> [f32; 3] {
+	[p.x(), p.y(), p.z()]
+}
+
+pub(crate) fn range(r: &Range2F) -> (f32, f32) {
+	(r.min_val(), r.max_val())
+}
+
+pub(crate) fn lerp(a: f32, b: f32, t: f32) -> f32 {
+	a + (b - a) * t
+}
+
+pub(crate) fn lerp_array<const N: usize>(a: [f32; N], b: [f32; N], t: f32) -> [f32; N] {
+	std::array::from_fn(|i| lerp(a[i], b[i], t))
+}
+
+pub(crate) fn mat_mul(a: &[f32; 9], b: &[f32; 9]) -> [f32; 9] {
+	std::array::from_fn(|i| {
+		let (row, col) = (i / 3, i % 3);
+		(0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).sum()
+	})
+}
+
+/// `None` if `m` is singular
+pub(crate) fn invert(m: &[f32; 9]) -> Option<[f32; 9]> {
+	let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+		m[r0 * 3 + c0] * m[r1 * 3 + c1] - m[r0 * 3 + c1] * m[r1 * 3 + c0]
+	};
+
+	#[rustfmt::skip]
+	let adjugate = [
+		cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2),
+		-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2),
+		cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1),
+	];
+
+	let determinant = m[0] * adjugate[0] + m[1] * adjugate[3] + m[2] * adjugate[6];
+	(determinant.abs() > f32::EPSILON).then(|| adjugate.map(|v| v / determinant))
+}
+
+/// Apply a homography to a point
+pub(crate) fn project(h: &[f32; 9], x: f32, y: f32) -> (f32, f32) {
+	let [u, v, w] = mat_vec(h, [x, y, 1.0]);
+	(u / w, v / w)
+}
+
+pub(crate) fn mat_vec(m: &[f32; 9], v: [f32; 3]) -> [f32; 3] {
+	std::array::from_fn(|row| dot([m[row * 3], m[row * 3 + 1], m[row * 3 + 2]], v))
+}
+
+pub(crate) fn transpose(m: &[f32; 9]) -> [f32; 9] {
+	std::array::from_fn(|i| m[(i % 3) * 3 + i / 3])
+}
+
+pub(crate) fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+	a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[
+		a[1] * b[2] - a[2] * b[1],
+		a[2] * b[0] - a[0] * b[2],
+		a[0] * b[1] - a[1] * b[0],
+	]
+}
+
+pub(crate) fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+pub(crate) fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub(crate) fn scale(v: [f32; 3], s: f32) -> [f32; 3] {
+	[v[0] * s, v[1] * s, v[2] * s]
+}
+
+pub(crate) fn normalize(v: [f32; 3]) -> [f32; 3] {
+	scale(v, 1.0 / dot(v, v).sqrt().max(f32::EPSILON))
+}
+
+/// Make the rows of a nearly-rotation matrix orthonormal again with
+/// Gram-Schmidt
+pub(crate) fn orthonormalize(m: &[f32; 9]) -> [f32; 9] {
+	let x = normalize([m[0], m[1], m[2]]);
+	let y = [m[3], m[4], m[5]];
+	let y = normalize(sub(y, scale(x, dot(x, y))));
+	let z = cross(x, y);
+
+	[x[0], x[1], x[2], y[0], y[1], y[2], z[0], z[1], z[2]]
+}
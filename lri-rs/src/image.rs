@@ -0,0 +1,161 @@
+/// An image of linear `f32` samples with the channels of a pixel next to each
+/// other, rows top to bottom.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FloatImage {
+	pub width: usize,
+	pub height: usize,
+	pub channels: usize,
+	pub data: Vec<f32>,
+}
+
+impl FloatImage {
+	/// An image of all zeros
+	pub fn new(width: usize, height: usize, channels: usize) -> Self {
+		Self {
+			width,
+			height,
+			channels,
+			data: vec![0.0; width * height * channels],
+		}
+	}
+
+	/// # Panics
+	/// If `data` isn't `width * height * channels` long
+	pub fn from_data(width: usize, height: usize, channels: usize, data: Vec<f32>) -> Self {
+		assert_eq!(data.len(), width * height * channels);
+
+		Self {
+			width,
+			height,
+			channels,
+			data,
+		}
+	}
+
+	pub fn get(&self, x: usize, y: usize, channel: usize) -> f32 {
+		self.data[(y * self.width + x) * self.channels + channel]
+	}
+
+	pub fn set(&mut self, x: usize, y: usize, channel: usize, value: f32) {
+		self.data[(y * self.width + x) * self.channels + channel] = value;
+	}
+
+	/// A gray image at half the size of a 2x2 CFA mosaic, every pixel being
+	/// the mean of a cell of the mosaic. An odd last row or column is dropped.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn binned(mosaic: &[f32], width: usize, height: usize) -> Self {
+		assert_eq!(mosaic.len(), width * height);
+
+		let mut out = Self::new(width / 2, height / 2, 1);
+		for y in 0..out.height {
+			for x in 0..out.width {
+				let at = |dx: usize, dy: usize| mosaic[(y * 2 + dy) * width + x * 2 + dx];
+				out.data[y * out.width + x] = (at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0;
+			}
+		}
+
+		out
+	}
+
+	/// Like [FloatImage::binned] but with red, green, and blue channels from
+	/// the cell's pixels of that colour, the greens averaged. `cfa` is the CFA
+	/// of the mosaic as it's laid out in `mosaic`. With `None`, for monochrome
+	/// sensors, every channel is the gray.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn binned_rgb(mosaic: &[f32], width: usize, height: usize, cfa: Option<&str>) -> Self {
+		let Some(cfa) = cfa else {
+			let gray = Self::binned(mosaic, width, height);
+			let data = gray.data.iter().flat_map(|v| [*v; 3]).collect();
+			return Self::from_data(gray.width, gray.height, 3, data);
+		};
+		assert_eq!(mosaic.len(), width * height);
+
+		let mut out = Self::new(width / 2, height / 2, 3);
+		for y in 0..out.height {
+			for x in 0..out.width {
+				let mut rgb = [0.0; 3];
+				for (idx, color) in cfa.chars().enumerate() {
+					let v = mosaic[(y * 2 + idx / 2) * width + x * 2 + idx % 2];
+					match color {
+						'R' => rgb[0] += v,
+						'G' => rgb[1] += v / 2.0,
+						_ => rgb[2] += v,
+					}
+				}
+
+				for (c, v) in rgb.into_iter().enumerate() {
+					out.set(x, y, c, v);
+				}
+			}
+		}
+
+		out
+	}
+
+	/// Bilinearly interpolate one channel at a point between pixels, pixel
+	/// centers being whole numbers. `None` if it's outside of the image.
+	pub fn sample(&self, x: f32, y: f32, channel: usize) -> Option<f32> {
+		sample_bilinear(x, y, self.width, self.height, |x, y| {
+			self.get(x, y, channel)
+		})
+	}
+}
+
+/// Bilinearly interpolate at `x`, `y` in a `width` by `height` grid whose
+/// points are given by `at`. Points right on the last row or column are
+/// still inside. `None` if it's outside the grid.
+pub(crate) fn sample_bilinear<F: Fn(usize, usize) -> f32>(
+	x: f32,
+	y: f32,
+	width: usize,
+	height: usize,
+	at: F,
+) -> Option<f32> {
+	if width == 0 || height == 0 {
+		return None;
+	}
+
+	if !(x >= 0.0 && y >= 0.0 && x <= (width - 1) as f32 && y <= (height - 1) as f32) {
+		return None;
+	}
+
+	let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+	let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+	let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+	let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+	let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+	Some(top * (1.0 - fy) + bottom * fy)
+}
+
+/// The mean of the `radius` window around every sample. The window is cut
+/// short at the edges rather than padded.
+pub(crate) fn box_mean(data: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+	let mut horizontal = vec![0.0; data.len()];
+	for (row, out) in data
+		.chunks_exact(width)
+		.zip(horizontal.chunks_exact_mut(width))
+	{
+		for (x, out) in out.iter_mut().enumerate() {
+			let window = &row[x.saturating_sub(radius)..(x + radius + 1).min(width)];
+			*out = window.iter().sum::<f32>() / window.len() as f32;
+		}
+	}
+
+	let mut out = vec![0.0; data.len()];
+	for y in 0..height {
+		let rows = y.saturating_sub(radius)..(y + radius + 1).min(height);
+		let count = rows.len() as f32;
+
+		for x in 0..width {
+			let sum: f32 = rows.clone().map(|y| horizontal[y * width + x]).sum();
+			out[y * width + x] = sum / count;
+		}
+	}
+
+	out
+}
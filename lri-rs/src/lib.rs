@@ -2,37 +2,118 @@ use std::time::Duration;
 
 use block::{Block, ExtractedData, Header};
 
+pub mod aperture;
+mod bayer;
 mod block;
+mod calibration;
+pub mod crosstalk;
+pub mod defect;
+pub mod denoise;
+pub mod depth;
+pub mod distortion;
+mod error;
+pub mod fusion;
+pub mod geometry;
+mod image;
+pub mod mirror;
+pub mod superres;
 mod types;
-
+pub mod unpack;
+pub mod vignetting;
+pub mod zoom;
+
+pub use aperture::Aperture;
+pub use bayer::BayerJpegHeader;
+#[cfg(feature = "bayer-jpeg")]
+pub use bayer::{BayerJpegError, BayerJpegReconstruction};
+pub use calibration::{DeadPixelMap, HotPixelMeasurement, ModuleCalibration};
+pub use crosstalk::CrosstalkGrid;
+pub use depth::DepthOptions;
+pub use distortion::{CraDistortion, Distortion, PolynomialDistortion, Rect};
+pub use error::LriError;
+pub use fusion::FusionOptions;
+pub use geometry::{
+	CanonicalExtrinsics, Extrinsics, FocusCalibration, Geometry, Intrinsics, MirrorType, Pose,
+};
+pub use image::FloatImage;
+pub use mirror::{ActuatorTransformation, MirrorActuatorMapping, MirrorSystem, QuadraticModel};
+pub use superres::SuperResolutionOptions;
 pub use types::*;
+pub use vignetting::{MirrorVignetting, Vignetting, VignettingGrid};
+pub use zoom::ZoomOptions;
 
 pub struct LriFile<'lri> {
 	pub image_reference_camera: Option<CameraId>,
 	pub images: Vec<RawImage<'lri>>,
 	pub colors: Vec<ColorInfo>,
 	pub camera_infos: Vec<CameraInfo>,
+	pub calibrations: Vec<ModuleCalibration>,
+	pub sensor_profiles: Vec<SensorProfile>,
+
+	pub image_id: Option<ImageId>,
+	pub device_id: Option<DeviceId>,
+	/// The model name of the camera, like "L16"
+	pub model_name: Option<String>,
 
+	pub capture_time: Option<DateTime>,
+	/// When the device-wide factory calibration was done
+	pub device_calibration_time: Option<DateTime>,
 	pub focal_length: Option<i32>,
 	pub firmware_version: Option<String>,
+	/// Firmware version of the camera's ASIC
+	pub asic_firmware_version: Option<String>,
 	pub image_integration_time: Option<Duration>,
 	pub af_achieved: Option<bool>,
 	pub image_gain: Option<f32>,
+	/// The aperture picked on the camera for the depth of field to be
+	/// rendered at. See [aperture].
+	pub f_number: Option<f32>,
 	pub hdr: Option<HdrMode>,
 	pub scene: Option<SceneMode>,
 	pub on_tripod: Option<bool>,
 	pub awb: Option<AwbMode>,
 	pub awb_gain: Option<AwbGain>,
+	/// Where the photo was taken, if the camera knew
+	pub gps: Option<GpsInfo>,
 }
 
 impl<'lri> LriFile<'lri> {
 	/// Read
-	pub fn decode(mut data: &'lri [u8]) -> Self {
+	///
+	/// # Panics
+	/// If the file is malformed. See [LriFile::try_decode] for a version that
+	/// returns an error instead.
+	pub fn decode(data: &'lri [u8]) -> Self {
+		match Self::try_decode(data) {
+			Ok(lri) => lri,
+			Err(e) => panic!("{e}"),
+		}
+	}
+
+	/// Read, returning an [LriError] if the file is malformed
+	pub fn try_decode(data: &'lri [u8]) -> Result<Self, LriError> {
+		Self::decode_blocks(data, false).map(|(lri, _)| lri)
+	}
+
+	/// Read as much of a truncated file as we can.
+	///
+	/// Every complete block is decoded and, from the block that was cut off,
+	/// we keep the images that lie entirely within the data we have. What was
+	/// lost is described by the returned [Truncation]. Files that are
+	/// malformed in other ways still return an [LriError].
+	pub fn recover(data: &'lri [u8]) -> Result<(Self, Truncation), LriError> {
+		Self::decode_blocks(data, true)
+	}
+
+	fn decode_blocks(mut data: &'lri [u8], recover: bool) -> Result<(Self, Truncation), LriError> {
 		let mut images = vec![];
 		let mut colors = vec![];
 		let mut camera_infos = vec![];
 
 		let mut ext = ExtractedData::default();
+		let mut truncation = Truncation::default();
+		let mut index = 0;
+		let mut offset = 0;
 
 		// Read data blocks and extract informtion we care about
 		loop {
@@ -40,20 +121,57 @@ impl<'lri> LriFile<'lri> {
 				break;
 			}
 
-			let header = Header::ingest(&data[..]);
-			let end = header.block_length as usize;
+			let header = match Header::ingest(data, index, offset) {
+				// We can't know how long the block was meant to be, only that
+				// the header should've been there.
+				Err(LriError::TruncatedHeader { .. }) if recover => {
+					truncation.missing_bytes = Header::LENGTH - data.len();
+					break;
+				}
+				res => res?,
+			};
+			let end = header.block_length;
+
+			if end < Header::LENGTH {
+				return Err(LriError::UnsupportedValue {
+					block: index,
+					offset: offset + 4,
+					field: "block length",
+					value: end as i64,
+				});
+			}
+
+			if end > data.len() {
+				if !recover {
+					return Err(LriError::TruncatedBlock {
+						block: index,
+						offset,
+						missing: end - data.len(),
+					});
+				}
+
+				truncation.missing_bytes = end - data.len();
+			}
 
-			let block_data = &data[..end];
-			data = &data[end..];
+			let (block_data, rest) = data.split_at(end.min(data.len()));
+			data = rest;
 
 			let block = Block {
+				index,
+				offset,
 				header,
 				data: block_data,
 			};
 
-			block.extract_meaningful_data(&mut ext, &mut images, &mut colors, &mut camera_infos);
+			block.extract_meaningful_data(&mut ext, &mut images, &mut colors, &mut camera_infos)?;
+
+			index += 1;
+			offset += block_data.len();
 		}
 
+		truncation.lost_modules = ext.lost_modules;
+		truncation.lost_message = ext.lost_message;
+
 		// Further fill in the RawImage's we extracted
 		for img in images.iter_mut() {
 			if let Some(info) = camera_infos.iter().find(|i| i.camera == img.camera) {
@@ -67,25 +185,44 @@ impl<'lri> LriFile<'lri> {
 				.collect();
 
 			img.color = profiles;
+
+			img.sensor_profile = ext
+				.sensor_profiles
+				.iter()
+				.find(|sp| sp.sensor == img.sensor)
+				.cloned();
 		}
 
-		LriFile {
+		let lri = LriFile {
 			image_reference_camera: ext.reference_camera,
 			images,
 			colors,
 			camera_infos,
+			calibrations: ext.calibrations,
+			sensor_profiles: ext.sensor_profiles,
+
+			image_id: ext.image_id,
+			device_id: ext.device_id,
+			model_name: ext.model_name,
 
+			capture_time: ext.capture_time,
+			device_calibration_time: ext.device_calibration_time,
 			firmware_version: ext.fw_version,
+			asic_firmware_version: ext.asic_fw_version,
 			focal_length: ext.focal_length,
 			image_integration_time: ext.image_integration_time,
 			af_achieved: ext.af_achieved,
 			image_gain: ext.image_gain,
+			f_number: ext.f_number,
 			hdr: ext.hdr,
 			scene: ext.scene,
 			on_tripod: ext.on_tripod,
 			awb: ext.awb,
 			awb_gain: ext.awb_gain,
-		}
+			gps: ext.gps,
+		};
+
+		Ok((lri, truncation))
 	}
 
 	/// Number of images present in the file
@@ -94,23 +231,56 @@ impl<'lri> LriFile<'lri> {
 	}
 
 	/// Iterator over the images
-	pub fn images(&self) -> std::slice::Iter<'_, RawImage> {
+	pub fn images(&self) -> std::slice::Iter<'_, RawImage<'lri>> {
 		self.images.iter()
 	}
 
+	/// When the photo was taken, according to the camera's clock
+	pub fn capture_time(&self) -> Option<DateTime> {
+		self.capture_time
+	}
+
+	/// Get the factory calibration for a specific camera module
+	pub fn calibration(&self, camera: CameraId) -> Option<&ModuleCalibration> {
+		self.calibrations.iter().find(|cal| cal.camera == camera)
+	}
+
+	/// Get the factory characterization of a sensor model
+	pub fn sensor_profile(&self, sensor: SensorModel) -> Option<&SensorProfile> {
+		self.sensor_profiles.iter().find(|sp| sp.sensor == sensor)
+	}
+
 	/// Get the image the camera showed in the viewfinder, if it's been
 	/// recorded in the file.
-	pub fn reference_image(&self) -> Option<&RawImage> {
+	pub fn reference_image(&self) -> Option<&RawImage<'lri>> {
 		self.image_reference_camera
-			.map(|irc| self.images().find(|ri| ri.camera == irc))
-			.flatten()
+			.and_then(|irc| self.images().find(|ri| ri.camera == irc))
+	}
+}
+
+/// What was missing from a file read with [LriFile::recover]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Truncation {
+	/// How many bytes the last block was short by. If the file ended in the
+	/// middle of a block header this only counts the rest of the header.
+	pub missing_bytes: usize,
+	/// Modules that were described in the file but whose image data was cut off
+	pub lost_modules: Vec<CameraId>,
+	/// The message of the last block was cut off, so there may have been
+	/// modules in it that we can't know about.
+	pub lost_message: bool,
+}
+
+impl Truncation {
+	/// Whether the file was read in its entirety
+	pub fn is_complete(&self) -> bool {
+		self.missing_bytes == 0 && self.lost_modules.is_empty() && !self.lost_message
 	}
 }
 
 pub enum RawData<'img> {
 	BayerJpeg {
-		header: &'img [u8],
-		format: u32,
+		header: BayerJpegHeader<'img>,
 		jpeg0: &'img [u8],
 		jpeg1: &'img [u8],
 		jpeg2: &'img [u8],
@@ -119,6 +289,38 @@ pub enum RawData<'img> {
 	Packed10bpp {
 		data: &'img [u8],
 	},
+	Packed12bpp {
+		data: &'img [u8],
+	},
+	Packed14bpp {
+		data: &'img [u8],
+	},
+}
+
+/// What [RawImage::linear_mosaic] does beyond unpacking
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MosaicOptions {
+	/// [Correct defects](RawImage::correct_defects) from the calibration's
+	/// pixel maps. Off by default: how the maps are stored is a guess and the
+	/// coordinates could be garbage.
+	pub defects: bool,
+	/// How hard to [denoise](crate::denoise), 1.0 being what the noise model
+	/// says is there, or `None` to leave the noise alone. Off by default: we
+	/// haven't checked that the noise models' gains are in the same units as
+	/// [ModuleCapture::analog_gain], so the model picked could be the wrong one.
+	pub denoise: Option<f32>,
+	/// [Correct crosstalk](RawImage::correct_crosstalk) with the
+	/// calibration's matrices. Off by default: what the matrices mean and
+	/// which part of the sensor the grid covers are guesses.
+	pub crosstalk: bool,
+	/// [Flat-field](RawImage::flat_field) with the calibration's vignetting
+	/// grids. Off by default: which part of the sensor the grids cover is a
+	/// guess.
+	pub flat_field: bool,
+	/// [Undistort](RawImage::undistort_mosaic) with the calibration's
+	/// distortion model. Off by default: it resamples the mosaic, and we
+	/// haven't checked the model against straight lines in a real file.
+	pub undistort: bool,
 }
 
 pub struct RawImage<'img> {
@@ -129,17 +331,126 @@ pub struct RawImage<'img> {
 
 	pub width: usize,
 	pub height: usize,
+	/// Bytes from the start of one row to the next in the packed formats.
+	/// Zero for BayerJPEG.
+	pub row_stride: usize,
 
 	/// What format the data is in
 	pub format: DataFormat,
 	pub data: RawData<'img>,
+	/// Gain, exposure, and the like for the module that took this image
+	pub capture: ModuleCapture,
 	/// "sensor bayer red offset"
 	pub sbro: (i32, i32),
+	/// Where on the sensor the top-left of this image is, for cropped images.
+	pub start: (i32, i32),
+	/// The sensor was read out mirrored left-to-right
+	pub horizontal_flip: bool,
+	/// The sensor was read out mirrored top-to-bottom
+	pub vertical_flip: bool,
 	/// All color information associated with this [CameraId] for different [Whitepoint]s
 	pub color: Vec<ColorInfo>,
+	/// Levels and noise of this camera's [SensorModel]
+	pub sensor_profile: Option<SensorProfile>,
 }
 
 impl<'img> RawImage<'img> {
+	/// Unpack the image into one sample per pixel, in the order they're
+	/// stored in the file. Returns `None` for BayerJPEG, which isn't packed.
+	pub fn unpack(&self) -> Option<Vec<u16>> {
+		let mut out = vec![0; self.width * self.height];
+		self.unpack_into(&mut out).then_some(out)
+	}
+
+	/// Like [RawImage::unpack] but into a buffer you provide. Returns `false`,
+	/// leaving `out` untouched, if the image isn't in a packed format.
+	///
+	/// # Panics
+	/// If `out` is shorter than `width * height`.
+	pub fn unpack_into(&self, out: &mut [u16]) -> bool {
+		let (bits, data) = match self.data {
+			RawData::Packed10bpp { data } => (10, data),
+			RawData::Packed12bpp { data } => (12, data),
+			RawData::Packed14bpp { data } => (14, data),
+			RawData::BayerJpeg { .. } => return false,
+		};
+
+		unpack::unpack(bits, data, self.width, self.height, self.row_stride, out);
+		true
+	}
+
+	/// The black level in the units [RawImage::unpack] gives you, or
+	/// `reconstruct_bayer_jpeg` for BayerJPEG. We assume the higher bit
+	/// depth formats are the sensor's samples shifted up, so the level is too.
+	pub fn black_level(&self) -> Option<f32> {
+		self.sensor_profile
+			.as_ref()
+			.map(|sp| sp.black_level * self.level_scale())
+	}
+
+	/// The white level in the same units as [RawImage::black_level]
+	pub fn white_level(&self) -> Option<f32> {
+		self.sensor_profile
+			.as_ref()
+			.map(|sp| sp.white_level * self.level_scale())
+	}
+
+	/// Unpack the image into a linear mosaic, in the order it's stored in the
+	/// file, with the black level at 0.0 and the white level at 1.0. With a
+	/// `calibration` it has its defects, crosstalk, vignetting, and distortion
+	/// corrected too, if `options` asks for it. It's denoised if `options`
+	/// asks for it and we have a noise model for the sensor. With the default
+	/// options it's only unpacked and scaled.
+	///
+	/// Returns `None` for BayerJPEG, which isn't packed, and if we don't have
+	/// the [SensorProfile] to know the black and white levels.
+	pub fn linear_mosaic(
+		&self,
+		calibration: Option<&ModuleCalibration>,
+		options: &MosaicOptions,
+	) -> Option<Vec<f32>> {
+		let (black, white) = (self.black_level()?, self.white_level()?);
+		let mut unpacked = self.unpack()?;
+		if let Some(cal) = calibration.filter(|_| options.defects) {
+			self.correct_defects(cal, &mut unpacked);
+		}
+
+		// Denoising also gets rid of the black level and scales the white level to 1.0
+		let denoised = options
+			.denoise
+			.and_then(|strength| self.denoise_unpacked(&unpacked, strength));
+
+		let mut mosaic = match denoised {
+			Some(denoised) => denoised,
+			None => unpacked
+				.into_iter()
+				.map(|p| (p as f32 - black) / (white - black))
+				.collect(),
+		};
+
+		if let Some(cal) = calibration.filter(|_| options.crosstalk) {
+			self.correct_crosstalk(cal, &mut mosaic);
+		}
+
+		if let Some(cal) = calibration.filter(|_| options.flat_field) {
+			self.flat_field(cal, &mut mosaic);
+		}
+
+		if let Some(cal) = calibration.filter(|_| options.undistort) {
+			if let Some(undistorted) = self.undistort_mosaic(cal, &mosaic) {
+				mosaic = undistorted;
+			}
+		}
+
+		Some(mosaic)
+	}
+
+	fn level_scale(&self) -> f32 {
+		let sensor_bits = self.sensor.bit_depth().unwrap_or(10);
+		let bits = self.format.bits_per_pixel().unwrap_or(sensor_bits);
+		2f32.powi(bits as i32 - sensor_bits as i32)
+	}
+
 	/// Get the color profile for noon daylight. First looks for F7 and, if it can't find that, D65
 	pub fn daylight(&self) -> Option<&ColorInfo> {
 		self.color
@@ -153,28 +464,64 @@ impl<'img> RawImage<'img> {
 		self.color.iter().find(|c| c.whitepoint == whitepoint)
 	}
 
+	/// The CFA of the mosaic rotated 180° from how it's stored in the file,
+	/// which is the orientation prism has always debayered in. For the data
+	/// as it's stored use [RawImage::stored_cfa_string], and for the mosaic
+	/// after [RawImage::make_upright] use [RawImage::upright_cfa_string].
+	/// The CFA of the sensor, going by [RawImage::sbro]. `None` for mono
+	/// sensors, sensors we don't know the CFA of, and offsets we haven't seen.
 	pub fn cfa_string(&self) -> Option<&'static str> {
-		match self.sensor {
-			SensorModel::Ar1335Mono => None,
-			SensorModel::Ar1335 => self.cfa_string_ar1335(),
-			_ => unimplemented!(),
-		}
+		cfa_string(self.sensor, self.sbro)
+	}
+
+	/// The CFA of the mosaic in the order it's stored in the file
+	pub fn stored_cfa_string(&self) -> Option<&'static str> {
+		self.cfa_string()
+			.map(|cfa| flip_cfa(cfa, true, true, self.width, self.height))
 	}
 
-	// The AR1335 seems to be BGGR, which was weird.
-	fn cfa_string_ar1335(&self) -> Option<&'static str> {
-		//if self.format == DataFormat::BayerJpeg {
-		//	Some("BGGR")
-		//} else {
-		match self.sbro {
-			(-1, -1) => None,
-			(0, 0) => Some("BGGR"),
-			(1, 0) => Some("GRBG"),
-			(0, 1) => Some("GBRG"),
-			(1, 1) => Some("RGGB"),
-			_ => unreachable!(),
+	/// The CFA of the mosaic after it's been through [RawImage::make_upright]
+	pub fn upright_cfa_string(&self) -> Option<&'static str> {
+		self.stored_cfa_string().map(|cfa| {
+			flip_cfa(
+				cfa,
+				self.horizontal_flip,
+				self.vertical_flip,
+				self.width,
+				self.height,
+			)
+		})
+	}
+
+	/// Flip a mosaic, in the order it's stored in the file, so that it's
+	/// upright. Modules that read their sensor out mirrored, which are
+	/// usually the ones looking through a mirror, get un-mirrored here.
+	///
+	/// Works on anything with one value per pixel, so it can be used before
+	/// or after converting the samples.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long.
+	pub fn make_upright<T>(&self, mosaic: &mut [T]) {
+		assert_eq!(
+			mosaic.len(),
+			self.width * self.height,
+			"mosaic should be width * height long"
+		);
+
+		if self.horizontal_flip {
+			mosaic
+				.chunks_exact_mut(self.width)
+				.for_each(|row| row.reverse());
+		}
+
+		if self.vertical_flip {
+			for y in 0..self.height / 2 {
+				let (top, bottom) = mosaic.split_at_mut((self.height - 1 - y) * self.width);
+				top[y * self.width..(y + 1) * self.width]
+					.swap_with_slice(&mut bottom[..self.width]);
+			}
 		}
-		//}
 	}
 
 	/// Uses the [SensorModel] to determine if the image's [ColorType].
@@ -189,6 +536,63 @@ impl<'img> RawImage<'img> {
 	}
 }
 
+fn cfa_string(sensor: SensorModel, sbro: (i32, i32)) -> Option<&'static str> {
+	match sensor {
+		SensorModel::Ar1335 => cfa_string_ar1335(sbro),
+		SensorModel::Unknown
+		| SensorModel::Ar835
+		| SensorModel::Ar1335Mono
+		| SensorModel::Imx386
+		| SensorModel::Imx386Mono => None,
+	}
+}
+
+// The AR1335 seems to be BGGR, which was weird.
+fn cfa_string_ar1335(sbro: (i32, i32)) -> Option<&'static str> {
+	//if self.format == DataFormat::BayerJpeg {
+	//	Some("BGGR")
+	//} else {
+	match sbro {
+		(0, 0) => Some("BGGR"),
+		(1, 0) => Some("GRBG"),
+		(0, 1) => Some("GBRG"),
+		(1, 1) => Some("RGGB"),
+		// (-1, -1) is what mono modules have, anything else is a file we
+		// don't understand
+		_ => None,
+	}
+	//}
+}
+
+/// Work out what happens to a 2x2 CFA pattern when a `width` by `height`
+/// mosaic is mirrored.
+fn flip_cfa(
+	cfa: &'static str,
+	horizontal: bool,
+	vertical: bool,
+	width: usize,
+	height: usize,
+) -> &'static str {
+	let pattern = cfa.as_bytes();
+	// The colour that ends up at (x, y) after flipping. Only the parity of
+	// the position matters and width - 1 - x has the same parity as
+	// width + 1 + x, which can't underflow.
+	let at = |x: usize, y: usize| {
+		let x = if horizontal { width + 1 + x } else { x };
+		let y = if vertical { height + 1 + y } else { y };
+		pattern[(y % 2) * 2 + (x % 2)]
+	};
+
+	match &[at(0, 0), at(1, 0), at(0, 1), at(1, 1)] {
+		b"RGGB" => "RGGB",
+		b"BGGR" => "BGGR",
+		b"GRBG" => "GRBG",
+		b"GBRG" => "GBRG",
+		// Flipping can only move the colours around
+		_ => unreachable!(),
+	}
+}
+
 pub enum ColorType {
 	Rgb,
 	Grayscale,
@@ -223,3 +627,239 @@ pub struct CameraInfo {
 	camera: CameraId,
 	sensor: SensorModel,
 }
+
+#[cfg(test)]
+mod test {
+	use lri_proto::{
+		camera_id::CameraID,
+		camera_module::{camera_module::surface::FormatType, camera_module::Surface, CameraModule},
+		lightheader::LightHeader,
+		point2i::Point2I,
+		Message,
+	};
+
+	use super::*;
+
+	/// Where the image is in a [block], after the header and message
+	const IMAGE_OFFSET: usize = 256;
+	/// An 8x2 Packed10bpp image
+	const IMAGE_LENGTH: usize = 20;
+
+	fn point(x: i32, y: i32) -> Point2I {
+		let mut point = Point2I::new();
+		point.set_x(x);
+		point.set_y(y);
+		point
+	}
+
+	/// A LightHeader block with a message at the start, right after the
+	/// header, and an image for `camera` at [IMAGE_OFFSET]. Returns the block
+	/// and how long its message is.
+	fn block(camera: CameraID) -> (Vec<u8>, usize) {
+		let mut surface = Surface::new();
+		surface.start = Some(point(0, 0)).into();
+		surface.size = Some(point(8, 2)).into();
+		surface.set_format(FormatType::RAW_PACKED_10BPP);
+		surface.set_row_stride(10);
+		surface.set_data_offset(IMAGE_OFFSET as u64);
+
+		let mut module = CameraModule::new();
+		module.set_id(camera);
+		module.set_lens_position(0);
+		module.set_sensor_analog_gain(1.0);
+		module.set_sensor_exposure(0);
+		module.sensor_data_surface = Some(surface).into();
+		module.sensor_bayer_red_override = Some(point(0, 0)).into();
+
+		let mut light_header = LightHeader::new();
+		light_header.modules.push(module);
+		let message = light_header.write_to_bytes().unwrap();
+		assert!(Header::LENGTH + message.len() <= IMAGE_OFFSET);
+
+		let length = IMAGE_OFFSET + IMAGE_LENGTH;
+		let mut data = vec![0; length];
+		data[0..4].copy_from_slice(b"LELR");
+		data[4..12].copy_from_slice(&(length as u64).to_le_bytes());
+		data[12..20].copy_from_slice(&(Header::LENGTH as u64).to_le_bytes());
+		data[20..24].copy_from_slice(&(message.len() as u32).to_le_bytes());
+		// Block type 0 is a LightHeader
+		data[24] = 0;
+		data[Header::LENGTH..Header::LENGTH + message.len()].copy_from_slice(&message);
+
+		(data, message.len())
+	}
+
+	#[test]
+	fn decode_complete_block() {
+		let (data, _) = block(CameraID::A1);
+
+		let lri = LriFile::try_decode(&data).unwrap();
+		assert_eq!(lri.image_count(), 1);
+		assert_eq!(lri.images[0].camera, CameraId::A1);
+
+		let (lri, truncation) = LriFile::recover(&data).unwrap();
+		assert_eq!(lri.image_count(), 1);
+		assert!(truncation.is_complete());
+	}
+
+	#[test]
+	fn bad_magic() {
+		let (mut data, _) = block(CameraID::A1);
+		data[0] = b'X';
+
+		let expected = LriError::BadMagic {
+			block: 0,
+			offset: 0,
+			found: *b"XELR",
+		};
+		assert_eq!(LriFile::try_decode(&data).err(), Some(expected.clone()));
+		assert_eq!(LriFile::recover(&data).err(), Some(expected));
+	}
+
+	#[test]
+	fn unknown_block_type() {
+		let (mut data, _) = block(CameraID::A1);
+		data[24] = 7;
+
+		let expected = LriError::UnknownBlockType {
+			block: 0,
+			offset: 0,
+			kind: 7,
+		};
+		assert_eq!(LriFile::try_decode(&data).err(), Some(expected.clone()));
+		assert_eq!(LriFile::recover(&data).err(), Some(expected));
+	}
+
+	#[test]
+	fn block_shorter_than_header() {
+		let (mut data, _) = block(CameraID::A1);
+		data[4..12].copy_from_slice(&16u64.to_le_bytes());
+
+		let expected = LriError::UnsupportedValue {
+			block: 0,
+			offset: 4,
+			field: "block length",
+			value: 16,
+		};
+		assert_eq!(LriFile::try_decode(&data).err(), Some(expected.clone()));
+		assert_eq!(LriFile::recover(&data).err(), Some(expected));
+	}
+
+	#[test]
+	fn truncated_header() {
+		let (first, _) = block(CameraID::A1);
+		let (second, _) = block(CameraID::A2);
+		let data = [first.as_slice(), &second[..10]].concat();
+
+		assert_eq!(
+			LriFile::try_decode(&data).err(),
+			Some(LriError::TruncatedHeader {
+				block: 1,
+				offset: first.len(),
+			})
+		);
+
+		// The first block is still there
+		let (lri, truncation) = LriFile::recover(&data).unwrap();
+		assert_eq!(lri.image_count(), 1);
+		assert_eq!(truncation.missing_bytes, Header::LENGTH - 10);
+		assert!(truncation.lost_modules.is_empty());
+	}
+
+	#[test]
+	fn truncated_image() {
+		let (first, _) = block(CameraID::A1);
+		let (second, _) = block(CameraID::A2);
+		let cut = IMAGE_OFFSET + IMAGE_LENGTH / 2;
+		let data = [first.as_slice(), &second[..cut]].concat();
+
+		assert_eq!(
+			LriFile::try_decode(&data).err(),
+			Some(LriError::TruncatedBlock {
+				block: 1,
+				offset: first.len(),
+				missing: second.len() - cut,
+			})
+		);
+
+		let (lri, truncation) = LriFile::recover(&data).unwrap();
+		assert_eq!(lri.image_count(), 1);
+		assert_eq!(lri.images[0].camera, CameraId::A1);
+		assert_eq!(truncation.missing_bytes, second.len() - cut);
+		assert_eq!(truncation.lost_modules, vec![CameraId::A2]);
+		assert!(!truncation.lost_message);
+	}
+
+	#[test]
+	fn truncated_message() {
+		let (data, message_length) = block(CameraID::A1);
+		let cut = Header::LENGTH + message_length / 2;
+
+		let (lri, truncation) = LriFile::recover(&data[..cut]).unwrap();
+		assert_eq!(lri.image_count(), 0);
+		assert_eq!(truncation.missing_bytes, data.len() - cut);
+		assert!(truncation.lost_message);
+	}
+
+	#[test]
+	fn recover_every_cut() {
+		let (data, message_length) = block(CameraID::A1);
+
+		for cut in 0..=data.len() {
+			let truncated = &data[..cut];
+			let (lri, truncation) = LriFile::recover(truncated).unwrap();
+
+			if cut == data.len() {
+				assert!(LriFile::try_decode(truncated).is_ok());
+				assert!(truncation.is_complete());
+				assert_eq!(lri.image_count(), 1);
+				continue;
+			}
+
+			assert!(LriFile::try_decode(truncated).is_err() || cut == 0);
+			assert_eq!(lri.image_count(), 0);
+
+			if cut == 0 {
+				assert!(truncation.is_complete());
+			} else if cut < Header::LENGTH {
+				assert_eq!(truncation.missing_bytes, Header::LENGTH - cut);
+			} else {
+				assert_eq!(truncation.missing_bytes, data.len() - cut);
+				assert_eq!(
+					truncation.lost_message,
+					cut < Header::LENGTH + message_length
+				);
+				assert_eq!(truncation.lost_modules.is_empty(), truncation.lost_message);
+			}
+		}
+	}
+	#[test]
+	fn cfa_string_unknown() {
+		assert_eq!(cfa_string(SensorModel::Ar1335, (1, 1)), Some("RGGB"));
+		assert_eq!(cfa_string(SensorModel::Ar1335, (-1, -1)), None);
+		assert_eq!(cfa_string(SensorModel::Ar1335, (2, 0)), None);
+		assert_eq!(cfa_string(SensorModel::Ar1335, (0, -7)), None);
+		assert_eq!(cfa_string(SensorModel::Ar1335Mono, (0, 0)), None);
+		assert_eq!(cfa_string(SensorModel::Ar835, (0, 0)), None);
+		assert_eq!(cfa_string(SensorModel::Imx386, (0, 0)), None);
+		assert_eq!(cfa_string(SensorModel::Unknown, (0, 0)), None);
+	}
+
+	#[test]
+	fn flip_cfa_even_size() {
+		assert_eq!(flip_cfa("RGGB", false, false, 4, 4), "RGGB");
+		assert_eq!(flip_cfa("RGGB", true, false, 4, 4), "GRBG");
+		assert_eq!(flip_cfa("RGGB", false, true, 4, 4), "GBRG");
+		assert_eq!(flip_cfa("RGGB", true, true, 4, 4), "BGGR");
+		assert_eq!(flip_cfa("GBRG", true, false, 4, 4), "BGGR");
+	}
+
+	#[test]
+	fn flip_cfa_odd_size() {
+		// The last column of an odd width mosaic starts with the same colour
+		// as the first
+		assert_eq!(flip_cfa("RGGB", true, false, 3, 4), "RGGB");
+		assert_eq!(flip_cfa("RGGB", false, true, 4, 3), "RGGB");
+		assert_eq!(flip_cfa("RGGB", true, true, 3, 4), "GBRG");
+	}
+}
@@ -0,0 +1,364 @@
+//! The movable mirrors some of the modules look through.
+//!
+//! A module with a movable mirror isn't calibrated with a fixed pose. Instead
+//! we get where the real camera is, how the mirror turns, and how to get from
+//! the mirror's hall sensor code to its angle. [MirrorActuatorMapping::angle]
+//! does the latter and [MirrorSystem::virtual_pose] turns that into the pose of
+//! the camera you'd see in the mirror, which is the one the image looks like
+//! it came from.
+//!
+//! We take angles to be in degrees and the mirror to turn the right-handed
+//! way around `rotation_axis`.
+
+use lri_proto::mirror_system::{
+	mirror_actuator_mapping::TransformationType, MirrorActuatorMapping as PbActuatorMapping,
+	MirrorSystem as PbMirrorSystem,
+};
+
+use crate::{
+	geometry::{
+		add, cross, dot, mat_mul, mat_vec, matrix3x3, normalize, range, scale, sub, vector3,
+		IDENTITY,
+	},
+	Pose,
+};
+
+/// The geometry of a mirror and the camera looking into it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorSystem {
+	/// Where the camera behind the mirror is
+	pub real_camera_location: [f32; 3],
+	/// Which way the camera behind the mirror points, row-major
+	pub real_camera_orientation: [f32; 9],
+	/// The mirror turns around this axis...
+	pub rotation_axis: [f32; 3],
+	/// ...which goes through this point
+	pub point_on_rotation_axis: [f32; 3],
+	/// How far the mirror's surface is from `point_on_rotation_axis`
+	pub mirror_distance: f32,
+	/// The mirror's normal when it's at an angle of zero
+	pub mirror_normal_at_zero: [f32; 3],
+	/// The image has to be flipped around the x axis. We think this undoes
+	/// the mirroring the mirror does.
+	pub flip_img_around_x: bool,
+	/// The angles the mirror can be at
+	pub mirror_angle_range: Option<(f32, f32)>,
+	pub reprojection_error: Option<f32>,
+}
+
+impl MirrorSystem {
+	/// The mirror's normal with it turned to `angle`
+	pub fn normal(&self, angle: f32) -> [f32; 3] {
+		normalize(rotate(
+			self.mirror_normal_at_zero,
+			normalize(self.rotation_axis),
+			angle.to_radians(),
+		))
+	}
+
+	/// The pose of the camera's reflection with the mirror at `angle`, in the
+	/// same world coordinates as the other modules.
+	///
+	/// We take `real_camera_orientation` to be a world to camera rotation like
+	/// [Pose::rotation] and the mirror's surface to be `mirror_distance` out
+	/// along its normal from `point_on_rotation_axis`.
+	///
+	/// A reflection isn't a rotation, so with `flip_img_around_x` set the pose
+	/// is for the image flipped upside down, which is one. Without it the
+	/// rotation is left mirrored.
+	pub fn virtual_pose(&self, angle: f32) -> Pose {
+		let normal = self.normal(angle);
+		let surface = add(
+			self.point_on_rotation_axis,
+			scale(normal, self.mirror_distance),
+		);
+		let plane = dot(normal, surface);
+
+		// A point x reflects to householder * x + 2 * plane * normal
+		let householder: [f32; 9] =
+			std::array::from_fn(|i| IDENTITY[i] - 2.0 * normal[i / 3] * normal[i % 3]);
+
+		let rotation = mat_mul(&self.real_camera_orientation, &householder);
+		let translation = mat_vec(
+			&self.real_camera_orientation,
+			sub(scale(normal, 2.0 * plane), self.real_camera_location),
+		);
+
+		if self.flip_img_around_x {
+			let flip = [1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 1.0];
+			Pose {
+				rotation: mat_mul(&flip, &rotation),
+				translation: mat_vec(&flip, translation),
+			}
+		} else {
+			Pose {
+				rotation,
+				translation,
+			}
+		}
+	}
+}
+
+/// Rotate `v` by `angle` radians around the unit vector `axis`, Rodrigues'
+/// rotation formula.
+fn rotate(v: [f32; 3], axis: [f32; 3], angle: f32) -> [f32; 3] {
+	let (sin, cos) = angle.sin_cos();
+
+	add(
+		add(scale(v, cos), scale(cross(axis, v), sin)),
+		scale(axis, dot(axis, v) * (1.0 - cos)),
+	)
+}
+
+impl From<&PbMirrorSystem> for MirrorSystem {
+	fn from(ms: &PbMirrorSystem) -> Self {
+		Self {
+			real_camera_location: vector3(ms.real_camera_location.get_or_default()),
+			real_camera_orientation: matrix3x3(ms.real_camera_orientation.get_or_default()),
+			rotation_axis: vector3(ms.rotation_axis.get_or_default()),
+			point_on_rotation_axis: vector3(ms.point_on_rotation_axis.get_or_default()),
+			mirror_distance: ms.distance_mirror_plane_to_point_on_rotation_axis(),
+			mirror_normal_at_zero: vector3(ms.mirror_normal_at_zero_degrees.get_or_default()),
+			flip_img_around_x: ms.flip_img_around_x(),
+			mirror_angle_range: ms.mirror_angle_range.as_ref().map(range),
+			reprojection_error: ms.reprojection_error,
+		}
+	}
+}
+
+/// How to get the mirror's angle from its hall sensor code
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorActuatorMapping {
+	pub transformation: ActuatorTransformation,
+	pub actuator_length_offset: f32,
+	pub actuator_length_scale: f32,
+	pub mirror_angle_offset: f32,
+	pub mirror_angle_scale: f32,
+	/// Measured hall codes and the angle the mirror was at
+	pub angle_pairs: Vec<(i32, f32)>,
+	pub quadratic: Option<QuadraticModel>,
+	pub angle_to_hall_code_error: Option<f32>,
+	pub hall_code_to_angle_error: Option<f32>,
+	pub hall_code_range: Option<(f32, f32)>,
+}
+
+impl MirrorActuatorMapping {
+	/// The mirror's angle at `hall_code`.
+	///
+	/// If there are at least two measured [angle_pairs](Self::angle_pairs) we
+	/// interpolate between them, extending the ends. Otherwise we use the
+	/// quadratic model. `None` if there's neither.
+	pub fn angle(&self, hall_code: i32) -> Option<f32> {
+		self.angle_from_pairs(hall_code)
+			.or_else(|| self.angle_from_quadratic(hall_code))
+	}
+
+	fn angle_from_pairs(&self, hall_code: i32) -> Option<f32> {
+		let mut pairs = self.angle_pairs.clone();
+		pairs.sort_by_key(|(code, _)| *code);
+		pairs.dedup_by_key(|(code, _)| *code);
+
+		if pairs.len() < 2 {
+			return None;
+		}
+
+		let idx = pairs
+			.windows(2)
+			.position(|pair| hall_code <= pair[1].0)
+			.unwrap_or(pairs.len() - 2);
+		let ((code0, angle0), (code1, angle1)) = (pairs[idx], pairs[idx + 1]);
+
+		// The codes come from the file, so subtracting them as i32 can overflow
+		let t = (hall_code as i64 - code0 as i64) as f64 / (code1 as i64 - code0 as i64) as f64;
+		Some(angle0 + (angle1 - angle0) * t as f32)
+	}
+
+	/// We think the quadratic model goes from a normalized angle to a
+	/// normalized hall code, `coeffs[0] + coeffs[1] * a + coeffs[2] * a^2`, so
+	/// to go backwards we solve it and pick the root the segment says to.
+	/// Hall codes are normalized by subtracting `actuator_length_offset` and
+	/// dividing by `actuator_length_scale`, angles the other way around with
+	/// the mirror angle offset and scale. For [ActuatorTransformation::TanHalfTheta]
+	/// the normalized angle is of `tan(angle / 2)`.
+	fn angle_from_quadratic(&self, hall_code: i32) -> Option<f32> {
+		let model = self.quadratic.as_ref()?;
+		let (c0, c1, c2) = match model.coeffs.as_slice() {
+			[c0, c1] => (*c0, *c1, 0.0),
+			[c0, c1, c2, ..] => (*c0, *c1, *c2),
+			_ => return None,
+		};
+
+		let hall = (hall_code as f32 - self.actuator_length_offset) / self.actuator_length_scale;
+
+		let normalized = if c2.abs() < f32::EPSILON {
+			(hall - c0) / c1
+		} else {
+			let discriminant = c1 * c1 - 4.0 * c2 * (c0 - hall);
+			if discriminant < 0.0 {
+				return None;
+			}
+
+			let rplus = if hall < model.inflection_value {
+				model.use_rplus_for_left_segment
+			} else {
+				model.use_rplus_for_right_segment
+			};
+
+			let root = discriminant.sqrt();
+			let root = if rplus { root } else { -root };
+			(-c1 + root) / (2.0 * c2)
+		};
+
+		let value = normalized * self.mirror_angle_scale + self.mirror_angle_offset;
+		let angle = match self.transformation {
+			ActuatorTransformation::MeanStdNormalize => value,
+			ActuatorTransformation::TanHalfTheta => (2.0 * value.atan()).to_degrees(),
+		};
+
+		angle.is_finite().then_some(angle)
+	}
+}
+
+impl From<&PbActuatorMapping> for MirrorActuatorMapping {
+	fn from(mam: &PbActuatorMapping) -> Self {
+		Self {
+			transformation: mam.transformation_type().into(),
+			actuator_length_offset: mam.actuator_length_offset(),
+			actuator_length_scale: mam.actuator_length_scale(),
+			mirror_angle_offset: mam.mirror_angle_offset(),
+			mirror_angle_scale: mam.mirror_angle_scale(),
+			angle_pairs: mam
+				.actuator_angle_pair_vec
+				.iter()
+				.map(|pair| (pair.hall_code(), pair.angle()))
+				.collect(),
+			quadratic: mam.quadratic_model.as_ref().map(|qm| QuadraticModel {
+				use_rplus_for_left_segment: qm.use_rplus_for_left_segment(),
+				use_rplus_for_right_segment: qm.use_rplus_for_right_segment(),
+				inflection_value: qm.inflection_value(),
+				coeffs: qm.model_coeffs.clone(),
+			}),
+			angle_to_hall_code_error: mam.angle_to_hall_code_error,
+			hall_code_to_angle_error: mam.hall_code_to_angle_error,
+			hall_code_range: mam.hall_code_range.as_ref().map(range),
+		}
+	}
+}
+
+/// How the hall code and angle are normalized before the quadratic model
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ActuatorTransformation {
+	MeanStdNormalize,
+	TanHalfTheta,
+}
+
+impl From<TransformationType> for ActuatorTransformation {
+	fn from(tt: TransformationType) -> Self {
+		match tt {
+			TransformationType::MEAN_STD_NORMALIZE => Self::MeanStdNormalize,
+			TransformationType::TAN_HALF_THETA => Self::TanHalfTheta,
+		}
+	}
+}
+
+/// A quadratic fit of normalized angle to normalized hall code, split into
+/// two segments at `inflection_value`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuadraticModel {
+	pub use_rplus_for_left_segment: bool,
+	pub use_rplus_for_right_segment: bool,
+	pub inflection_value: f32,
+	pub coeffs: Vec<f32>,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::geometry::transpose;
+
+	/// A camera at the origin looking down z at a mirror turning around y
+	fn system(flip: bool) -> MirrorSystem {
+		MirrorSystem {
+			real_camera_location: [0.0, 0.0, 0.0],
+			real_camera_orientation: IDENTITY,
+			rotation_axis: [0.0, 1.0, 0.0],
+			point_on_rotation_axis: [0.0, 0.0, 20.0],
+			mirror_distance: 1.0,
+			mirror_normal_at_zero: normalize([1.0, 0.0, -1.0]),
+			flip_img_around_x: flip,
+			mirror_angle_range: None,
+			reprojection_error: None,
+		}
+	}
+
+	fn determinant(m: &[f32; 9]) -> f32 {
+		dot(
+			[m[0], m[1], m[2]],
+			cross([m[3], m[4], m[5]], [m[6], m[7], m[8]]),
+		)
+	}
+
+	#[test]
+	fn virtual_pose_is_a_rotation() {
+		for angle in [-10.0, 0.0, 3.5, 10.0] {
+			let rotation = system(true).virtual_pose(angle).rotation;
+			assert!((determinant(&rotation) - 1.0).abs() < 1e-5, "{angle}");
+
+			let identity = mat_mul(&rotation, &transpose(&rotation));
+			for (a, b) in identity.iter().zip(IDENTITY) {
+				assert!((a - b).abs() < 1e-5, "{angle}");
+			}
+
+			// Without the flip it's left mirrored
+			let mirrored = system(false).virtual_pose(angle).rotation;
+			assert!((determinant(&mirrored) + 1.0).abs() < 1e-5, "{angle}");
+		}
+	}
+
+	fn mapping(angle_pairs: Vec<(i32, f32)>) -> MirrorActuatorMapping {
+		MirrorActuatorMapping {
+			transformation: ActuatorTransformation::MeanStdNormalize,
+			actuator_length_offset: 0.0,
+			actuator_length_scale: 1.0,
+			mirror_angle_offset: 0.0,
+			mirror_angle_scale: 1.0,
+			angle_pairs,
+			quadratic: None,
+			angle_to_hall_code_error: None,
+			hall_code_to_angle_error: None,
+			hall_code_range: None,
+		}
+	}
+
+	#[test]
+	fn angle_between_pairs() {
+		let mapping = mapping(vec![(100, 1.0), (300, 5.0), (200, 2.0)]);
+		assert_eq!(mapping.angle(150), Some(1.5));
+		assert_eq!(mapping.angle(250), Some(3.5));
+		// Extends the ends
+		assert_eq!(mapping.angle(0), Some(0.0));
+		assert_eq!(mapping.angle(400), Some(8.0));
+
+		// Codes far enough apart to overflow an i32 when subtracted
+		let mapping = self::mapping(vec![(i32::MIN, -1.0), (i32::MAX, 1.0)]);
+		assert_eq!(mapping.angle(0), Some(0.0));
+		assert_eq!(mapping.angle(i32::MAX), Some(1.0));
+	}
+
+	#[test]
+	fn virtual_camera_is_reflected() {
+		let system = system(true);
+		let normal = system.normal(0.0);
+		let surface = add(
+			system.point_on_rotation_axis,
+			scale(normal, system.mirror_distance),
+		);
+
+		// Both cameras are as far from the mirror as each other, on opposite
+		// sides of it
+		let center = system.virtual_pose(0.0).center();
+		let real = dot(sub(system.real_camera_location, surface), normal);
+		let virt = dot(sub(center, surface), normal);
+		assert!((real + virt).abs() < 1e-4);
+	}
+}
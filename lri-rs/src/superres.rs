@@ -0,0 +1,350 @@
+//! Super-resolution from the C modules.
+//!
+//! The C modules look through mirrors, which leave them pointed a fraction of
+//! a pixel apart from each other. Registered to the reference like in
+//! [fusion](crate::fusion), the pixels they saw land between the reference's,
+//! so putting them on a finer grid, rather than averaging them onto the
+//! reference's, recovers detail none of them have on their own. This is
+//! shift-and-add: every sample of every module's full resolution mosaic is
+//! spread over the pixels of the finer grid around it, in the channel of its
+//! colour, and each pixel is the weighted mean of what landed on it. Pixels
+//! nothing landed on are filled in from the reference. Nothing is sharpened
+//! afterwards.
+//!
+//! Modules are lined up on their half resolution
+//! [color views](crate::depth::View::from_image_rgb) and `scale` is relative
+//! to those. The default of 4 is twice the sensor's resolution each way, about
+//! 52 MP from the 13 MP modules. How much of that is real detail depends on how
+//! well the modules' offsets fill the gaps between each other's samples: six
+//! modules give about one red or blue sample for every three pixels of the
+//! result and one green for every one and a half.
+//!
+//! A sample is left out if it's outside of the range of the reference's pixels
+//! around it, give or take `tolerance`, so things that moved don't ghost but
+//! samples on an edge, which land between the values on either side of it,
+//! are kept.
+
+use crate::{
+	fusion::{Aligned, Frame, SATURATED},
+	geometry::{invert, project},
+	CameraId, FloatImage, LriFile, ModuleCalibration, ModuleGroup, MosaicOptions, RawImage,
+};
+
+/// Radius of the tent every sample is spread over the grid with, in pixels of
+/// the half resolution views. Samples of one colour from one module are a
+/// pixel apart, so this leaves no gaps between them.
+const KERNEL_RADIUS: f32 = 0.75;
+
+/// How to go about super-resolution
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuperResolutionOptions {
+	/// The modules to use. `None` is the C modules.
+	pub cameras: Option<Vec<CameraId>>,
+	/// How many times larger each way than the half resolution views the
+	/// result is. 2 is the sensor's resolution.
+	pub scale: usize,
+	/// Distance to the plane modules are registered on, in the units of the
+	/// calibrated translations. `None` is the distance the reference was
+	/// focused at, or infinity if we don't know that.
+	pub plane_distance: Option<f32>,
+	/// The furthest to look when lining a module up, in pixels of the half
+	/// resolution views
+	pub search_radius: usize,
+	/// How far outside of the range of the reference's pixels around it a
+	/// sample can be and still be used, relative to the brightest of them
+	pub tolerance: f32,
+	/// What to do to each module's mosaic before it's binned
+	pub mosaic: MosaicOptions,
+}
+
+impl Default for SuperResolutionOptions {
+	fn default() -> Self {
+		Self {
+			cameras: None,
+			scale: 4,
+			plane_distance: None,
+			search_radius: 8,
+			tolerance: 0.1,
+			mosaic: MosaicOptions::default(),
+		}
+	}
+}
+
+/// A module's [Frame] and the full resolution mosaic it was binned from
+#[derive(Clone, Debug, PartialEq)]
+pub struct MosaicFrame {
+	pub frame: Frame,
+	/// The module's [linear mosaic](RawImage::linear_mosaic)
+	pub mosaic: Vec<f32>,
+	pub width: usize,
+	pub height: usize,
+	/// The CFA of `mosaic` as it's laid out
+	pub cfa: &'static str,
+}
+
+impl MosaicFrame {
+	/// Returns `None` if we can't get a [Frame] of the image or it's
+	/// monochrome.
+	pub fn from_image(
+		image: &RawImage,
+		calibration: &ModuleCalibration,
+		options: &MosaicOptions,
+	) -> Option<Self> {
+		let cfa = image.stored_cfa_string()?;
+		let mosaic = image.linear_mosaic(Some(calibration), options)?;
+
+		Some(Self {
+			frame: Frame::from_mosaic(image, calibration, &mosaic)?,
+			mosaic,
+			width: image.width,
+			height: image.height,
+			cfa,
+		})
+	}
+
+	/// Where the sample at `x`, `y` of the mosaic is in pixels of the frame's
+	/// view, whose pixels are centered on 2x2 cells of the mosaic
+	fn in_view(&self, x: usize, y: usize) -> (f32, f32) {
+		let view_x = (x as f32 - 0.5) / 2.0;
+		let view_y = (y as f32 - 0.5) / 2.0;
+
+		if self.frame.view.flipped {
+			let height = self.frame.view.image.height;
+			(view_x, (height as f32 - 1.0) - view_y)
+		} else {
+			(view_x, view_y)
+		}
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// Super-resolve the modules in `options`, see [superres](crate::superres).
+	/// The result is aligned to the reference camera if it's one of them, or
+	/// else the first of them in the file, and in the units of its
+	/// [linear mosaic](RawImage::linear_mosaic) at its exposure.
+	///
+	/// Returns `None` if there are no such modules or we can't get a
+	/// [MosaicFrame] of the one we align to.
+	pub fn super_resolution(&self, options: &SuperResolutionOptions) -> Option<FloatImage> {
+		let wanted = |camera: CameraId| match &options.cameras {
+			Some(cameras) => cameras.contains(&camera),
+			None => camera.group() == ModuleGroup::C,
+		};
+		let frame = |img: &RawImage| {
+			MosaicFrame::from_image(img, self.calibration(img.camera)?, &options.mosaic)
+		};
+
+		let reference = self
+			.reference_image()
+			.filter(|img| wanted(img.camera))
+			.or_else(|| self.images().find(|img| wanted(img.camera)))?;
+
+		let others: Vec<MosaicFrame> = self
+			.images()
+			.filter(|img| img.camera != reference.camera && wanted(img.camera))
+			.filter_map(frame)
+			.collect();
+
+		let plane_distance = options
+			.plane_distance
+			.or_else(|| self.focus_distance(reference))
+			.unwrap_or(f32::INFINITY);
+
+		Some(super_resolve(
+			&frame(reference)?,
+			&others,
+			plane_distance,
+			options,
+		))
+	}
+}
+
+/// Shift-and-add the mosaics of `others` and `reference` onto a grid
+/// `options.scale` times finer than `reference`'s view, registering them on a
+/// plane `plane_distance` in front of it. The result has three channels.
+///
+/// At the default scale it's over half a gigabyte, and twice that while it's
+/// being put together.
+pub fn super_resolve(
+	reference: &MosaicFrame,
+	others: &[MosaicFrame],
+	plane_distance: f32,
+	options: &SuperResolutionOptions,
+) -> FloatImage {
+	let image = &reference.frame.view.image;
+	let scale = options.scale.max(1);
+	let mut grid = Grid::new(image.width * scale, image.height * scale, scale);
+
+	grid.add(reference, image, options.tolerance, 1.0, |x, y| (x, y));
+
+	for other in others {
+		let Some(aligned) = Aligned::new(
+			&reference.frame,
+			&other.frame,
+			plane_distance,
+			options.search_radius,
+		) else {
+			continue;
+		};
+		let Some(inverse) = invert(&aligned.homography) else {
+			continue;
+		};
+
+		// Undo the homography and then the shift
+		grid.add(other, image, options.tolerance, aligned.scale, |x, y| {
+			let (x, y) = project(&inverse, x, y);
+			(x - aligned.shift.0, y - aligned.shift.1)
+		});
+	}
+
+	grid.finish(image, reference.frame.sensitivity * 0.01)
+}
+
+/// Whether `value` is within the range of the reference's pixels around `x`,
+/// `y`, give or take `tolerance`. Anything goes where the reference clipped.
+fn plausible(
+	reference: &FloatImage,
+	x: f32,
+	y: f32,
+	channel: usize,
+	value: f32,
+	tolerance: f32,
+) -> bool {
+	let (x, y) = (x.round(), y.round());
+	if x < 0.0 || y < 0.0 || x >= reference.width as f32 || y >= reference.height as f32 {
+		return false;
+	}
+
+	let (x, y) = (x as usize, y as usize);
+	let (mut low, mut high) = (f32::INFINITY, f32::NEG_INFINITY);
+	for ny in y.saturating_sub(1)..(y + 2).min(reference.height) {
+		for nx in x.saturating_sub(1)..(x + 2).min(reference.width) {
+			let v = reference.get(nx, ny, channel);
+			if v >= SATURATED {
+				return true;
+			}
+
+			low = low.min(v);
+			high = high.max(v);
+		}
+	}
+
+	let allowed = high.abs() * tolerance + 1e-3;
+	value >= low - allowed && value <= high + allowed
+}
+
+/// Sums of weighted samples on the finer grid
+struct Grid {
+	sums: FloatImage,
+	weights: FloatImage,
+	scale: usize,
+}
+
+impl Grid {
+	fn new(width: usize, height: usize, scale: usize) -> Self {
+		Self {
+			sums: FloatImage::new(width, height, 3),
+			weights: FloatImage::new(width, height, 3),
+			scale,
+		}
+	}
+
+	/// Spread every sample of `frame` that isn't clipped and is
+	/// [plausible] over the grid. `to_reference` takes a point of the frame's
+	/// view to one of `reference`'s and `exposure` brings the frame to the
+	/// reference's exposure.
+	fn add<F: Fn(f32, f32) -> (f32, f32)>(
+		&mut self,
+		frame: &MosaicFrame,
+		reference: &FloatImage,
+		tolerance: f32,
+		exposure: f32,
+		to_reference: F,
+	) {
+		let cfa = frame.cfa.as_bytes();
+		for y in 0..frame.height {
+			for x in 0..frame.width {
+				let value = frame.mosaic[y * frame.width + x];
+				if value >= SATURATED {
+					continue;
+				}
+
+				let channel = match cfa[(y % 2) * 2 + x % 2] {
+					b'R' => 0,
+					b'G' => 1,
+					_ => 2,
+				};
+
+				let (view_x, view_y) = frame.in_view(x, y);
+				let (rx, ry) = to_reference(view_x, view_y);
+				let value = value * exposure;
+				if !plausible(reference, rx, ry, channel, value, tolerance) {
+					continue;
+				}
+
+				self.splat(rx, ry, channel, value, frame.frame.sensitivity);
+			}
+		}
+	}
+
+	/// Spread `value`, at `x`, `y` in pixels of the reference's view, over the
+	/// pixels of the grid within [KERNEL_RADIUS] of it
+	fn splat(&mut self, x: f32, y: f32, channel: usize, value: f32, weight: f32) {
+		let (width, height) = (self.sums.width, self.sums.height);
+		let scale = self.scale as f32;
+		let radius = KERNEL_RADIUS * scale;
+
+		// Pixel centers are whole numbers on both
+		let gx = (x + 0.5) * scale - 0.5;
+		let gy = (y + 0.5) * scale - 0.5;
+
+		let span = |center: f32, len: usize| {
+			let first = (center - radius).ceil().max(0.0) as usize;
+			let last = (center + radius).floor().min(len as f32 - 1.0);
+			first..(last + 1.0).max(0.0) as usize
+		};
+
+		for py in span(gy, height) {
+			let wy = 1.0 - (py as f32 - gy).abs() / radius;
+			for px in span(gx, width) {
+				let w = wy * (1.0 - (px as f32 - gx).abs() / radius) * weight;
+				if w <= 0.0 {
+					continue;
+				}
+
+				let sum = self.sums.get(px, py, channel);
+				self.sums.set(px, py, channel, sum + value * w);
+				let total = self.weights.get(px, py, channel);
+				self.weights.set(px, py, channel, total + w);
+			}
+		}
+	}
+
+	/// Divide out the weights. Pixels with less than `min_weight` are
+	/// interpolated from `reference` instead.
+	fn finish(mut self, reference: &FloatImage, min_weight: f32) -> FloatImage {
+		let (width, height) = (self.sums.width, self.sums.height);
+		let scale = self.scale as f32;
+		let max_x = reference.width.saturating_sub(1) as f32;
+		let max_y = reference.height.saturating_sub(1) as f32;
+
+		for y in 0..height {
+			for x in 0..width {
+				for c in 0..3 {
+					let weight = self.weights.get(x, y, c);
+					let value = if weight >= min_weight {
+						self.sums.get(x, y, c) / weight
+					} else {
+						let rx = ((x as f32 + 0.5) / scale - 0.5).clamp(0.0, max_x);
+						let ry = ((y as f32 + 0.5) / scale - 0.5).clamp(0.0, max_y);
+						reference.sample(rx, ry, c).unwrap_or(0.0)
+					};
+
+					self.sums.set(x, y, c, value);
+				}
+			}
+		}
+
+		self.sums
+	}
+}
@@ -1,11 +1,24 @@
 /// Responsible for mapping generated protobuf enums to enums defined here. It
 /// seemed like a bad idea to rexport from lri-proto.
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use lri_proto::{
-	camera_id::CameraID as PbCameraID, camera_module::camera_module::surface::FormatType,
+	camera_id::CameraID as PbCameraID,
+	camera_module::{
+		camera_module::{surface::FormatType, AFInfo},
+		CameraModule,
+	},
 	color_calibration::color_calibration::IlluminantType,
+	gps_data::{
+		gpsdata::{ProcessingMethod, ReferenceAltitude, ReferenceNorth},
+		GPSData,
+	},
+	sensor_characterization::sensor_characterization::{
+		vst_noise_model::VstModel as PbVstModel, VstNoiseModel,
+	},
+	time_stamp::TimeStamp,
 	view_preferences::view_preferences::HDRMode,
+	EnumOrUnknown,
 };
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -13,9 +26,23 @@ use lri_proto::{
 pub enum DataFormat {
 	BayerJpeg,
 	Packed10bpp,
-	// Never seen
-	//Packed12bpp,
-	//Packed14bpp,
+	/// Never seen in the wild, but apparently some firmware writes it
+	Packed12bpp,
+	/// Never seen in the wild, but apparently some firmware writes it
+	Packed14bpp,
+}
+
+impl DataFormat {
+	/// How many bits each pixel takes up in the packed formats. BayerJPEG
+	/// isn't packed so it has `None`.
+	pub fn bits_per_pixel(&self) -> Option<usize> {
+		match self {
+			Self::BayerJpeg => None,
+			Self::Packed10bpp => Some(10),
+			Self::Packed12bpp => Some(12),
+			Self::Packed14bpp => Some(14),
+		}
+	}
 }
 
 impl fmt::Display for DataFormat {
@@ -23,27 +50,29 @@ impl fmt::Display for DataFormat {
 		let str = match self {
 			Self::BayerJpeg => "BayerJpeg",
 			Self::Packed10bpp => "Packed10bpp",
-			//Self::Packed12bpp => "Packed12bpp",
-			//Self::Packed14bpp => "Packed14bpp",
+			Self::Packed12bpp => "Packed12bpp",
+			Self::Packed14bpp => "Packed14bpp",
 		};
 
 		write!(f, "{str}")
 	}
 }
 
-impl From<FormatType> for DataFormat {
-	fn from(proto: FormatType) -> Self {
+impl TryFrom<FormatType> for DataFormat {
+	type Error = FormatType;
+
+	fn try_from(proto: FormatType) -> Result<Self, Self::Error> {
 		match proto {
-			FormatType::RAW_BAYER_JPEG => Self::BayerJpeg,
-			FormatType::RAW_PACKED_10BPP => Self::Packed10bpp,
-			FormatType::RAW_PACKED_12BPP => unreachable!(),
-			FormatType::RAW_PACKED_14BPP => unreachable!(),
+			FormatType::RAW_BAYER_JPEG => Ok(Self::BayerJpeg),
+			FormatType::RAW_PACKED_10BPP => Ok(Self::Packed10bpp),
+			FormatType::RAW_PACKED_12BPP => Ok(Self::Packed12bpp),
+			FormatType::RAW_PACKED_14BPP => Ok(Self::Packed14bpp),
 			FormatType::RAW_RESERVED_0
 			| FormatType::RAW_RESERVED_1
 			| FormatType::RAW_RESERVED_2
 			| FormatType::RAW_RESERVED_3
 			| FormatType::RAW_RESERVED_4
-			| FormatType::RAW_RESERVED_5 => unimplemented!(),
+			| FormatType::RAW_RESERVED_5 => Err(proto),
 		}
 	}
 }
@@ -91,6 +120,38 @@ impl From<PbCameraID> for CameraId {
 	}
 }
 
+impl CameraId {
+	/// Which group of modules this one is in
+	pub fn group(&self) -> ModuleGroup {
+		match self {
+			Self::A1 | Self::A2 | Self::A3 | Self::A4 | Self::A5 => ModuleGroup::A,
+			Self::B1 | Self::B2 | Self::B3 | Self::B4 | Self::B5 => ModuleGroup::B,
+			Self::C1 | Self::C2 | Self::C3 | Self::C4 | Self::C5 | Self::C6 => ModuleGroup::C,
+		}
+	}
+}
+
+/// The modules come in three groups that share a focal length
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ModuleGroup {
+	A,
+	B,
+	/// These look through movable mirrors
+	C,
+}
+
+impl ModuleGroup {
+	/// The 35mm equivalent focal length of the group, in millimeters, as
+	/// Light advertised it
+	pub fn focal_length(&self) -> f32 {
+		match self {
+			Self::A => 28.0,
+			Self::B => 70.0,
+			Self::C => 150.0,
+		}
+	}
+}
+
 impl fmt::Display for CameraId {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		// this is good; i write good code
@@ -110,23 +171,25 @@ pub enum Whitepoint {
 	TL84,
 }
 
-impl From<IlluminantType> for Whitepoint {
-	fn from(it: IlluminantType) -> Self {
+impl TryFrom<IlluminantType> for Whitepoint {
+	type Error = IlluminantType;
+
+	fn try_from(it: IlluminantType) -> Result<Self, Self::Error> {
 		match it {
-			IlluminantType::A => Self::A,
-			IlluminantType::D50 => Self::D50,
-			IlluminantType::D65 => Self::D65,
-			IlluminantType::D75 => Self::D75,
-			IlluminantType::F2 => Self::F2,
-			IlluminantType::F7 => Self::F7,
-			IlluminantType::F11 => Self::F11,
-			IlluminantType::TL84 => Self::TL84,
-			IlluminantType::UNKNOWN => unimplemented!(),
+			IlluminantType::A => Ok(Self::A),
+			IlluminantType::D50 => Ok(Self::D50),
+			IlluminantType::D65 => Ok(Self::D65),
+			IlluminantType::D75 => Ok(Self::D75),
+			IlluminantType::F2 => Ok(Self::F2),
+			IlluminantType::F7 => Ok(Self::F7),
+			IlluminantType::F11 => Ok(Self::F11),
+			IlluminantType::TL84 => Ok(Self::TL84),
+			IlluminantType::UNKNOWN => Err(it),
 		}
 	}
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum SensorModel {
 	Unknown,
 	Ar835,
@@ -136,6 +199,19 @@ pub enum SensorModel {
 	Imx386Mono,
 }
 
+impl SensorModel {
+	/// Bits per sample the sensor puts out. They're all 10-bit as far as we
+	/// know. `None` if we don't know the sensor.
+	pub fn bit_depth(&self) -> Option<usize> {
+		match self {
+			Self::Unknown => None,
+			Self::Ar835 | Self::Ar1335 | Self::Ar1335Mono | Self::Imx386 | Self::Imx386Mono => {
+				Some(10)
+			}
+		}
+	}
+}
+
 impl From<lri_proto::sensor_type::SensorType> for SensorModel {
 	fn from(pbst: lri_proto::sensor_type::SensorType) -> Self {
 		use lri_proto::sensor_type::SensorType as ProtoSt;
@@ -200,6 +276,13 @@ impl From<lri_proto::view_preferences::view_preferences::SceneMode> for SceneMod
 pub enum AwbMode {
 	Auto,
 	Daylight,
+	Shade,
+	Cloudy,
+	Tungsten,
+	Fluorescent,
+	Flash,
+	Custom,
+	Kelvin,
 }
 
 impl From<lri_proto::view_preferences::view_preferences::AWBMode> for AwbMode {
@@ -209,7 +292,13 @@ impl From<lri_proto::view_preferences::view_preferences::AWBMode> for AwbMode {
 		match awb {
 			PbAwbMode::AWB_MODE_AUTO => Self::Auto,
 			PbAwbMode::AWB_MODE_DAYLIGHT => Self::Daylight,
-			_ => panic!("{awb:?}"),
+			PbAwbMode::AWB_MODE_SHADE => Self::Shade,
+			PbAwbMode::AWB_MODE_CLOUDY => Self::Cloudy,
+			PbAwbMode::AWB_MODE_TUNGSTEN => Self::Tungsten,
+			PbAwbMode::AWB_MODE_FLUORESCENT => Self::Fluorescent,
+			PbAwbMode::AWB_MODE_FLASH => Self::Flash,
+			PbAwbMode::AWB_MODE_CUSTOM => Self::Custom,
+			PbAwbMode::AWB_MODE_KELVIN => Self::Kelvin,
 		}
 	}
 }
@@ -233,3 +322,389 @@ impl From<lri_proto::view_preferences::view_preferences::ChannelGain> for AwbGai
 		}
 	}
 }
+
+/// Location information the camera recorded with the photo. It follows the
+/// Android location API the L16 was built on, so angles are in degrees,
+/// altitude is in meters, and speed is in meters per second.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpsInfo {
+	/// Degrees north of the equator. Negative is south.
+	pub latitude: Option<f64>,
+	/// Degrees east of the prime meridian. Negative is west.
+	pub longitude: Option<f64>,
+	pub altitude: Option<Altitude>,
+	/// Which way the camera was facing
+	pub heading: Option<Bearing>,
+	/// Which way the camera was moving
+	pub track: Option<Bearing>,
+	pub speed: Option<f64>,
+	/// Dilution of precision
+	pub dop: Option<f64>,
+	/// When the fix was taken. Presumably milliseconds since the Unix epoch,
+	/// like Android's `Location.getTime()`.
+	pub timestamp: Option<u64>,
+	pub processing_method: Option<GpsProcessingMethod>,
+}
+
+impl From<GPSData> for GpsInfo {
+	fn from(gps: GPSData) -> Self {
+		// value and ref are marked as required in Track, Heading and Altitude
+		let bearing = |value: Option<f64>, north: Option<EnumOrUnknown<ReferenceNorth>>| Bearing {
+			value: value.unwrap(),
+			reference: north.unwrap().enum_value_or_default().into(),
+		};
+
+		Self {
+			latitude: gps.latitude,
+			longitude: gps.longitude,
+			altitude: gps.altitude.into_option().map(|alt| Altitude {
+				value: alt.value.unwrap(),
+				reference: alt.ref_.unwrap().enum_value_or_default().into(),
+			}),
+			heading: gps.heading.into_option().map(|h| bearing(h.value, h.ref_)),
+			track: gps.track.into_option().map(|t| bearing(t.value, t.ref_)),
+			speed: gps.speed,
+			dop: gps.dop,
+			timestamp: gps.timestamp,
+			processing_method: gps
+				.processing_method
+				.and_then(|ev| ev.enum_value().ok())
+				.map(<_>::into),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Altitude {
+	pub value: f64,
+	pub reference: AltitudeReference,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AltitudeReference {
+	SeaLevel,
+}
+
+impl From<ReferenceAltitude> for AltitudeReference {
+	fn from(ra: ReferenceAltitude) -> Self {
+		match ra {
+			ReferenceAltitude::REFERENCE_ALTITUDE_SEA_LEVEL => Self::SeaLevel,
+		}
+	}
+}
+
+/// A direction in degrees clockwise from north
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bearing {
+	pub value: f64,
+	pub reference: NorthReference,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NorthReference {
+	Magnetic,
+	True,
+}
+
+impl From<ReferenceNorth> for NorthReference {
+	fn from(rn: ReferenceNorth) -> Self {
+		match rn {
+			ReferenceNorth::REFERENCE_NORTH_MAGNETIC => Self::Magnetic,
+			ReferenceNorth::REFERENCE_NORTH_TRUE => Self::True,
+		}
+	}
+}
+
+/// How the location was determined
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GpsProcessingMethod {
+	Unknown,
+	Gps,
+	CellId,
+	Wlan,
+	Manual,
+	Fused,
+}
+
+impl From<ProcessingMethod> for GpsProcessingMethod {
+	fn from(pm: ProcessingMethod) -> Self {
+		match pm {
+			ProcessingMethod::PROCESSING_METHOD_UNKNOWN => Self::Unknown,
+			ProcessingMethod::PROCESSING_METHOD_GPS => Self::Gps,
+			ProcessingMethod::PROCESSING_METHOD_CELLID => Self::CellId,
+			ProcessingMethod::PROCESSING_METHOD_WLAN => Self::Wlan,
+			ProcessingMethod::PROCESSING_METHOD_MANUAL => Self::Manual,
+			ProcessingMethod::PROCESSING_METHOD_FUSED => Self::Fused,
+		}
+	}
+}
+
+/// A date and time as the camera recorded it. The time is local to wherever
+/// the camera was, and `utc_offset` says how far that was from UTC if known.
+///
+/// Ordering compares the fields as written, so it's only chronological for
+/// times with the same offset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+	pub year: u32,
+	pub month: u32,
+	pub day: u32,
+	pub hour: u32,
+	pub minute: u32,
+	pub second: u32,
+	/// Offset from UTC, straight from the file. The proto doesn't say what
+	/// unit it's in and we haven't checked it against a capture in a known
+	/// time zone. We assume minutes.
+	pub utc_offset: Option<i32>,
+}
+
+impl DateTime {
+	/// Formatted for EXIF's DateTimeOriginal. "YYYY:MM:DD HH:MM:SS"
+	pub fn exif(&self) -> String {
+		format!(
+			"{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+			self.year, self.month, self.day, self.hour, self.minute, self.second
+		)
+	}
+
+	/// Formatted for EXIF's OffsetTimeOriginal. "+HH:MM". This takes
+	/// `utc_offset` to be in minutes, which is unconfirmed.
+	pub fn exif_offset(&self) -> Option<String> {
+		self.utc_offset.map(|offset| {
+			let sign = if offset < 0 { '-' } else { '+' };
+			let offset = offset.unsigned_abs();
+			format!("{sign}{:02}:{:02}", offset / 60, offset % 60)
+		})
+	}
+}
+
+impl fmt::Display for DateTime {
+	/// ISO 8601, like 2018-07-04T21:30:00-05:00
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+			self.year, self.month, self.day, self.hour, self.minute, self.second
+		)?;
+
+		match self.exif_offset() {
+			Some(offset) => write!(f, "{offset}"),
+			None => Ok(()),
+		}
+	}
+}
+
+impl From<TimeStamp> for DateTime {
+	fn from(ts: TimeStamp) -> Self {
+		// Everything but tz_offset is marked as required in TimeStamp
+		Self {
+			year: ts.year(),
+			month: ts.month(),
+			day: ts.day(),
+			hour: ts.hour(),
+			minute: ts.minute(),
+			second: ts.second(),
+			utc_offset: ts.tz_offset,
+		}
+	}
+}
+
+/// Identifies a single photo. Every LRI the camera writes gets a new one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ImageId(pub u128);
+
+impl ImageId {
+	pub(crate) fn from_halves(low: Option<u64>, high: Option<u64>) -> Option<Self> {
+		join_halves(low, high).map(Self)
+	}
+}
+
+impl fmt::Display for ImageId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:032x}", self.0)
+	}
+}
+
+/// Identifies the physical camera that took a photo.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(pub u128);
+
+impl DeviceId {
+	pub(crate) fn from_halves(low: Option<u64>, high: Option<u64>) -> Option<Self> {
+		join_halves(low, high).map(Self)
+	}
+}
+
+impl fmt::Display for DeviceId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:032x}", self.0)
+	}
+}
+
+/// The LightHeader stores 128-bit IDs as two u64. We call it present if
+/// either half is.
+fn join_halves(low: Option<u64>, high: Option<u64>) -> Option<u128> {
+	if low.is_none() && high.is_none() {
+		return None;
+	}
+
+	let low = low.unwrap_or_default() as u128;
+	let high = high.unwrap_or_default() as u128;
+	Some((high << 64) | low)
+}
+
+/// How a camera module was set up when it took its image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleCapture {
+	/// Whether the module was in use for this photo
+	pub enabled: bool,
+	pub analog_gain: f32,
+	pub digital_gain: Option<f32>,
+	/// How long the sensor was exposed. Assumed to be stored in nanoseconds
+	/// like the integration time in the ViewPreferences.
+	pub exposure: Duration,
+	/// Where the lens actuator was, as a hall sensor code
+	pub lens_position: i32,
+	/// Where the mirror was, as a hall sensor code. Only meaningful for modules
+	/// with a movable mirror.
+	pub mirror_position: i32,
+	/// Temperature of the sensor, probably in °C
+	pub temperature: Option<i32>,
+	pub frame_index: Option<u32>,
+	/// Whether the sensor's own defective pixel correction was on
+	pub dpc_on: bool,
+	pub exposure_start_offset: Option<i32>,
+	pub scan_speed: Option<f32>,
+	/// Autofocus state of this module
+	pub af: Option<ModuleAf>,
+}
+
+impl ModuleCapture {
+	/// Analog gain multiplied by digital gain, if there was any
+	pub fn total_gain(&self) -> f32 {
+		self.analog_gain * self.digital_gain.unwrap_or(1.0)
+	}
+}
+
+impl From<&CameraModule> for ModuleCapture {
+	fn from(module: &CameraModule) -> Self {
+		Self {
+			enabled: module.is_enabled(),
+			analog_gain: module.sensor_analog_gain(),
+			digital_gain: module.sensor_digital_gain,
+			exposure: Duration::from_nanos(module.sensor_exposure()),
+			lens_position: module.lens_position(),
+			mirror_position: module.mirror_position(),
+			temperature: module.sensor_temparature,
+			frame_index: module.frame_index,
+			dpc_on: module.sensor_dpc_on(),
+			exposure_start_offset: module.sensor_exp_start_offset,
+			scan_speed: module.sensor_scan_speed,
+			af: module.af_info.as_ref().map(<_>::into),
+		}
+	}
+}
+
+/// Per-module autofocus information. The only mode the L16 has is auto, so
+/// that's left out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ModuleAf {
+	/// Center of the region focused on
+	pub roi_center: Option<(f32, f32)>,
+	pub disparity_focus_distance: Option<f32>,
+	pub contrast_focus_distance: Option<f32>,
+	/// The lens didn't reach its position in time
+	pub lens_timeout: Option<bool>,
+	/// The mirror didn't reach its position in time
+	pub mirror_timeout: Option<bool>,
+	pub mirror_position: Option<i32>,
+}
+
+impl From<&AFInfo> for ModuleAf {
+	fn from(af: &AFInfo) -> Self {
+		Self {
+			roi_center: af.roi_center.as_ref().map(|p| (p.x(), p.y())),
+			disparity_focus_distance: af.disparity_focus_distance,
+			contrast_focus_distance: af.contrast_focus_distance,
+			lens_timeout: af.lens_timeout,
+			mirror_timeout: af.mirror_timeout,
+			mirror_position: af.mirror_position,
+		}
+	}
+}
+
+/// Factory characterization of a sensor model. The levels are in the units of
+/// the sensor's own bit depth, see [SensorModel::bit_depth].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorProfile {
+	pub sensor: SensorModel,
+	pub black_level: f32,
+	pub white_level: f32,
+	/// We don't know what this is yet
+	pub cliff_slope: Option<f32>,
+	/// Noise models measured at different gains
+	pub noise_models: Vec<NoiseModel>,
+	/// When the sensor was characterized
+	pub time: Option<DateTime>,
+}
+
+impl SensorProfile {
+	/// The noise model measured at the gain closest to `gain`. We assume
+	/// [NoiseModel::gain] is in the same units as
+	/// [ModuleCapture::analog_gain], which we haven't been able to check
+	/// against a real file.
+	pub fn noise_model(&self, gain: f32) -> Option<&NoiseModel> {
+		self.noise_models.iter().min_by(|a, b| {
+			(a.gain as f32 - gain)
+				.abs()
+				.total_cmp(&(b.gain as f32 - gain).abs())
+		})
+	}
+}
+
+/// Parameters for a variance stabilizing transform at one sensor gain. Each
+/// channel has its own [VstModel].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NoiseModel {
+	/// The gain this was measured at. The units aren't known, it's stored as
+	/// an integer where the analog gain is a float.
+	pub gain: u32,
+	pub threshold: f32,
+	pub scale: f32,
+	pub red: VstModel,
+	pub green: VstModel,
+	pub blue: VstModel,
+	/// Only present for the mono sensors
+	pub panchromatic: Option<VstModel>,
+}
+
+impl From<&VstNoiseModel> for NoiseModel {
+	fn from(vst: &VstNoiseModel) -> Self {
+		Self {
+			gain: vst.gain(),
+			threshold: vst.threshold(),
+			scale: vst.scale(),
+			red: vst.red.get_or_default().into(),
+			green: vst.green.get_or_default().into(),
+			blue: vst.blue.get_or_default().into(),
+			panchromatic: vst.panchromatic.as_ref().map(<_>::into),
+		}
+	}
+}
+
+/// Noise of a single channel. The variance of a sample with the value `x` is
+/// assumed to be `a * x + b`, that's the usual Poisson-Gaussian model.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VstModel {
+	pub a: f32,
+	pub b: f32,
+}
+
+impl From<&PbVstModel> for VstModel {
+	fn from(vst: &PbVstModel) -> Self {
+		Self {
+			a: vst.a(),
+			b: vst.b(),
+		}
+	}
+}
@@ -0,0 +1,165 @@
+//! Turning packed sensor data into one `u16` per pixel.
+//!
+//! The 10-bit format is a little-endian bitstream: the first pixel of a row
+//! is in the lowest bits of the row's first byte. Each row starts on a new
+//! `row_stride` boundary and may be padded out to it.
+//!
+//! We've never seen a 12 or 14-bit file, so those are unpacked the same way on
+//! the guess that Light didn't do anything different for them. That's
+//! unverified.
+
+/// Unpack 10-bit data. See [unpack] for the arguments.
+pub fn tenbit(packed: &[u8], width: usize, height: usize, row_stride: usize, out: &mut [u16]) {
+	unpack(10, packed, width, height, row_stride, out)
+}
+
+/// Unpack 12-bit data. See [unpack] for the arguments.
+pub fn twelvebit(packed: &[u8], width: usize, height: usize, row_stride: usize, out: &mut [u16]) {
+	unpack(12, packed, width, height, row_stride, out)
+}
+
+/// Unpack 14-bit data. See [unpack] for the arguments.
+pub fn fourteenbit(packed: &[u8], width: usize, height: usize, row_stride: usize, out: &mut [u16]) {
+	unpack(14, packed, width, height, row_stride, out)
+}
+
+/// How many bytes a row of `width` pixels, `bits` each, takes up before padding
+pub fn row_length(bits: usize, width: usize) -> usize {
+	(width * bits).div_ceil(8)
+}
+
+/// Unpack `width * height` pixels of `bits` each from `packed` into `out`.
+/// Rows in `packed` are `row_stride` bytes apart.
+///
+/// # Panics
+/// If `bits` is more than 16, if `row_stride` is too short to hold a row, or
+/// if either buffer is too small.
+pub fn unpack(
+	bits: usize,
+	packed: &[u8],
+	width: usize,
+	height: usize,
+	row_stride: usize,
+	out: &mut [u16],
+) {
+	assert!(bits <= 16, "can't unpack more than 16 bits into a u16");
+
+	let row_len = row_length(bits, width);
+	if row_len > row_stride {
+		panic!("a row needs {row_len} bytes but the stride is {row_stride}")
+	}
+
+	let count = width * height;
+	if count > out.len() {
+		panic!(
+			"expected output buffer to be {count} pixels, got {} pixels",
+			out.len()
+		)
+	}
+
+	let required_len = match height {
+		0 => 0,
+		_ => (height - 1) * row_stride + row_len,
+	};
+	if required_len > packed.len() {
+		panic!(
+			"expected input to be at least {required_len} bytes, it was {}",
+			packed.len()
+		)
+	}
+
+	let mask = (1u32 << bits) - 1;
+	for (y, out_row) in out[..count].chunks_exact_mut(width.max(1)).enumerate() {
+		let row = &packed[y * row_stride..y * row_stride + row_len];
+
+		for (x, px) in out_row.iter_mut().enumerate() {
+			let bit = x * bits;
+			let byte = bit / 8;
+
+			// At most 16 bits starting up to 7 bits into a byte, so three
+			// bytes always hold the whole pixel
+			let mut word = [0u8; 4];
+			let available = (row.len() - byte).min(3);
+			word[..available].copy_from_slice(&row[byte..byte + available]);
+
+			*px = ((u32::from_le_bytes(word) >> (bit % 8)) & mask) as u16;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Two rows of `width` pixels packed with `row_stride`, the padding
+	/// filled with junk that mustn't end up in the pixels
+	fn padded(rows: [&[u8]; 2], row_stride: usize) -> Vec<u8> {
+		let mut packed = vec![0xEE; row_stride * 2];
+		packed[..rows[0].len()].copy_from_slice(rows[0]);
+		packed[row_stride..row_stride + rows[1].len()].copy_from_slice(rows[1]);
+		packed
+	}
+
+	#[test]
+	fn row_lengths() {
+		assert_eq!(row_length(10, 4), 5);
+		assert_eq!(row_length(10, 3), 4);
+		assert_eq!(row_length(12, 3), 5);
+		assert_eq!(row_length(14, 3), 6);
+	}
+
+	#[test]
+	fn unpack_tenbit() {
+		let packed = padded(
+			[
+				&[0xFF, 0x03, 0x50, 0x95, 0xAA],
+				&[0x00, 0xFC, 0x0F, 0x00, 0x00],
+			],
+			8,
+		);
+
+		let mut out = [0; 8];
+		tenbit(&packed, 4, 2, 8, &mut out);
+		assert_eq!(
+			out,
+			[0x3FF, 0x000, 0x155, 0x2AA, 0x000, 0x3FF, 0x000, 0x000]
+		);
+	}
+
+	#[test]
+	fn unpack_twelvebit() {
+		let packed = padded(
+			[
+				&[0xBC, 0x3A, 0x12, 0xFF, 0x0F],
+				&[0x01, 0x00, 0x00, 0x00, 0x08],
+			],
+			6,
+		);
+
+		let mut out = [0; 6];
+		twelvebit(&packed, 3, 2, 6, &mut out);
+		assert_eq!(out, [0xABC, 0x123, 0xFFF, 0x001, 0x000, 0x800]);
+	}
+
+	#[test]
+	fn unpack_fourteenbit() {
+		let packed = padded(
+			[
+				&[0xFF, 0x7F, 0x00, 0xA0, 0xAA, 0x02],
+				&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+			],
+			7,
+		);
+
+		let mut out = [0; 6];
+		fourteenbit(&packed, 3, 2, 7, &mut out);
+		assert_eq!(out, [0x3FFF, 0x0001, 0x2AAA, 0x0000, 0x0000, 0x1000]);
+	}
+
+	#[test]
+	#[should_panic]
+	fn stride_too_short() {
+		let mut out = [0; 4];
+		tenbit(&[0; 8], 4, 1, 4, &mut out);
+	}
+}
@@ -0,0 +1,180 @@
+//! Vignetting correction with the factory characterization.
+//!
+//! Each module has a coarse grid of how bright the image is across the frame
+//! and, for modules with a mirror, one for each of a few mirror positions. We
+//! take the grid's corners to be the corners of the sensor, see
+//! [RawImage::flat_field] for what we guess that is, and its values to be the
+//! brightness relative to the center, so flat-fielding divides by it.
+
+use crate::{ModuleCalibration, RawImage};
+
+/// Vignetting of a single camera module
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vignetting {
+	/// Grids for different mirror positions
+	pub models: Vec<MirrorVignetting>,
+	/// Probably how bright this module is compared to the others, but we don't
+	/// know what it's relative to or whether it's a multiplier, so
+	/// [RawImage::flat_field] leaves it alone.
+	pub relative_brightness: Option<f32>,
+	/// Where the lens was, as a hall sensor code, when this was measured
+	pub lens_position: Option<i32>,
+}
+
+impl Vignetting {
+	/// The grid measured with the mirror nearest `mirror_position`
+	pub fn grid_for(&self, mirror_position: i32) -> Option<&VignettingGrid> {
+		self.models
+			.iter()
+			.min_by_key(|m| m.mirror_position.abs_diff(mirror_position))
+			.map(|m| &m.grid)
+	}
+}
+
+/// Vignetting at one mirror position
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorVignetting {
+	/// Where the mirror was, as a hall sensor code
+	pub mirror_position: i32,
+	pub grid: VignettingGrid,
+}
+
+/// A `width` by `height` grid of brightness, in rows
+#[derive(Clone, Debug, PartialEq)]
+pub struct VignettingGrid {
+	width: usize,
+	height: usize,
+	data: Vec<f32>,
+}
+
+impl VignettingGrid {
+	/// Returns `None` if `data` isn't `width * height` long or the grid is empty
+	pub fn new(width: usize, height: usize, data: Vec<f32>) -> Option<Self> {
+		(width > 0 && height > 0 && data.len() == width * height).then_some(Self {
+			width,
+			height,
+			data,
+		})
+	}
+
+	pub fn width(&self) -> usize {
+		self.width
+	}
+
+	pub fn height(&self) -> usize {
+		self.height
+	}
+
+	pub fn data(&self) -> &[f32] {
+		&self.data
+	}
+
+	/// Bilinearly interpolate the grid. `u` and `v` go from 0.0 at the left and
+	/// top to 1.0 at the right and bottom and are clamped to that.
+	pub fn sample(&self, u: f32, v: f32) -> f32 {
+		bilinear(self.width, self.height, u, v)
+			.into_iter()
+			.map(|(idx, weight)| self.data[idx] * weight)
+			.sum()
+	}
+
+	/// Upsample the grid to `width` by `height`
+	pub fn upsample(&self, width: usize, height: usize) -> Vec<f32> {
+		let scale = |n: usize| (n.max(2) - 1) as f32;
+
+		let mut out = Vec::with_capacity(width * height);
+		for y in 0..height {
+			for x in 0..width {
+				out.push(self.sample(x as f32 / scale(width), y as f32 / scale(height)));
+			}
+		}
+
+		out
+	}
+}
+
+/// The four points of a `width` by `height` grid around `u` and `v`, which go
+/// from 0.0 to 1.0 across it, and how much each should be weighted.
+pub(crate) fn bilinear(width: usize, height: usize, u: f32, v: f32) -> [(usize, f32); 4] {
+	let x = u.clamp(0.0, 1.0) * (width - 1) as f32;
+	let y = v.clamp(0.0, 1.0) * (height - 1) as f32;
+
+	let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+	let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+	let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+	[
+		(y0 * width + x0, (1.0 - fx) * (1.0 - fy)),
+		(y0 * width + x1, fx * (1.0 - fy)),
+		(y1 * width + x0, (1.0 - fx) * fy),
+		(y1 * width + x1, fx * fy),
+	]
+}
+
+impl<'img> RawImage<'img> {
+	/// Flat-field a linear, black level subtracted, mosaic in the order it's
+	/// stored in the file, like what [RawImage::denoise] gives you. Uses the
+	/// grid measured nearest this module's mirror position.
+	///
+	/// The grid covers the sensor, so the mosaic is moved by
+	/// [RawImage::start]. How much of the sensor the factory measured isn't
+	/// in the file, so we guess that it's what's read out and take the image
+	/// to reach its bottom-right corner. If it was the full pixel array of
+	/// the datasheet instead, 4208 wide for the AR1335 where L16 images are
+	/// 4160, the grid is stretched a little too wide.
+	///
+	/// Returns `false`, leaving `mosaic` untouched, if `calibration` has no
+	/// vignetting.
+	///
+	/// # Panics
+	/// If `mosaic` isn't `width * height` long
+	pub fn flat_field(&self, calibration: &ModuleCalibration, mosaic: &mut [f32]) -> bool {
+		let Some(vignetting) = calibration.vignetting.as_ref() else {
+			return false;
+		};
+		let Some(grid) = vignetting.grid_for(self.capture.mirror_position) else {
+			return false;
+		};
+
+		let (start_x, start_y) = (self.start.0.max(0) as usize, self.start.1.max(0) as usize);
+		let sensor = (start_x + self.width, start_y + self.height);
+
+		flat_field(
+			mosaic,
+			self.width,
+			self.height,
+			(start_x, start_y),
+			sensor,
+			grid,
+		);
+		true
+	}
+}
+
+/// Divide every pixel of `mosaic` by `grid` stretched over a sensor
+/// `sensor_size` big. `offset` is where on the sensor the top-left of the
+/// mosaic is.
+///
+/// # Panics
+/// If `mosaic` isn't `width * height` long
+pub fn flat_field(
+	mosaic: &mut [f32],
+	width: usize,
+	height: usize,
+	offset: (usize, usize),
+	sensor_size: (usize, usize),
+	grid: &VignettingGrid,
+) {
+	assert_eq!(mosaic.len(), width * height);
+
+	let scale = |n: usize| (n.max(2) - 1) as f32;
+	let (scale_x, scale_y) = (scale(sensor_size.0), scale(sensor_size.1));
+
+	for (y, row) in mosaic.chunks_exact_mut(width.max(1)).enumerate() {
+		let v = (y + offset.1) as f32 / scale_y;
+		for (x, p) in row.iter_mut().enumerate() {
+			let gain = grid.sample((x + offset.0) as f32 / scale_x, v);
+			*p /= gain.max(f32::EPSILON);
+		}
+	}
+}
@@ -0,0 +1,186 @@
+//! Zooming to focal lengths between the groups', like Lumen did.
+//!
+//! The group with the next shorter focal length is cropped to the field of
+//! view asked for, and the group with the next longer one, which sees the
+//! middle of that in more detail, is registered into the middle of it. The
+//! two are blended across a margin at the edge of the longer group's image so
+//! there's no seam. Each group is [fused](crate::fusion) into one image first.
+
+use crate::{
+	depth::View,
+	fusion::{fuse, Aligned, Frame, FusionOptions},
+	geometry::project,
+	FloatImage, LriFile, ModuleGroup, MosaicOptions,
+};
+
+/// How to go about zooming
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoomOptions {
+	/// Distance to the plane the groups are registered on, in the units of
+	/// the calibrated translations. `None` is the distance the shorter group
+	/// was focused at, or infinity if we don't know that.
+	pub plane_distance: Option<f32>,
+	/// The furthest to look when lining images up, in pixels of the half
+	/// resolution views
+	pub search_radius: usize,
+	/// How wide the blend at the edge of the longer group's image is, as a
+	/// fraction of its width
+	pub feather: f32,
+	/// What to do to each module's mosaic before it's binned
+	pub mosaic: MosaicOptions,
+}
+
+impl Default for ZoomOptions {
+	fn default() -> Self {
+		Self {
+			plane_distance: None,
+			search_radius: 8,
+			feather: 0.1,
+			mosaic: MosaicOptions::default(),
+		}
+	}
+}
+
+impl<'lri> LriFile<'lri> {
+	/// The frame that was composed on the camera, at [LriFile::focal_length].
+	/// See [LriFile::zoom].
+	pub fn composed(&self, options: &ZoomOptions) -> Option<FloatImage> {
+		self.zoom(self.focal_length? as f32, options)
+	}
+
+	/// The photo at `focal_length`, 35mm equivalent in millimeters, which
+	/// can't be any shorter than the A modules'. It's the size of a half
+	/// resolution view of the shorter group and in the units of its
+	/// [linear mosaic](crate::RawImage::linear_mosaic) at its exposure.
+	///
+	/// Each group is aligned to the reference camera if it's in the group, or
+	/// else the first of its modules in the file. Returns `None` if we can't
+	/// get a [Frame] of the shorter group.
+	pub fn zoom(&self, focal_length: f32, options: &ZoomOptions) -> Option<FloatImage> {
+		let groups = [ModuleGroup::A, ModuleGroup::B, ModuleGroup::C];
+		let focal_length = focal_length.max(ModuleGroup::A.focal_length());
+
+		let wide = groups
+			.into_iter()
+			.rev()
+			.find(|g| g.focal_length() <= focal_length)?;
+		let tele = groups.into_iter().find(|g| g.focal_length() > focal_length);
+
+		let (wide_frame, plane_distance) = self.group_frame(wide, options)?;
+		let tele_frame = tele
+			.filter(|_| focal_length > wide.focal_length())
+			.and_then(|g| self.group_frame(g, options))
+			.map(|(frame, _)| frame);
+
+		Some(zoom(
+			&wide_frame,
+			wide.focal_length(),
+			tele_frame.as_ref(),
+			focal_length,
+			plane_distance,
+			options,
+		))
+	}
+
+	/// The modules of `group` fused into one frame, and the distance to
+	/// register other frames to it at
+	fn group_frame(&self, group: ModuleGroup, options: &ZoomOptions) -> Option<(Frame, f32)> {
+		let frame = |img| Frame::from_image(img, self.calibration(img.camera)?, &options.mosaic);
+
+		let (reference, reference_frame) = self
+			.reference_image()
+			.filter(|img| img.camera.group() == group)
+			.into_iter()
+			.chain(self.images().filter(|img| img.camera.group() == group))
+			.find_map(|img| Some((img, frame(img)?)))?;
+
+		let others: Vec<Frame> = self
+			.images()
+			.filter(|img| img.camera.group() == group && img.camera != reference.camera)
+			.filter_map(frame)
+			.collect();
+
+		let plane_distance = options
+			.plane_distance
+			.or_else(|| self.focus_distance(reference))
+			.unwrap_or(f32::INFINITY);
+
+		let fusion = FusionOptions {
+			search_radius: options.search_radius,
+			..Default::default()
+		};
+		let fused = fuse(&reference_frame, &others, plane_distance, &fusion);
+
+		Some((
+			Frame {
+				view: View {
+					image: fused,
+					..reference_frame.view
+				},
+				sensitivity: reference_frame.sensitivity,
+			},
+			plane_distance,
+		))
+	}
+}
+
+/// Crop `wide`, whose focal length is `wide_focal_length`, to `focal_length`
+/// and blend `tele` into it, registered on a plane `plane_distance` in front
+/// of `wide`. The crop is centred on `wide`'s principal point, which stays put.
+/// The result is the size of `wide`'s image. Parts of it outside of `wide` are
+/// 0.0.
+pub fn zoom(
+	wide: &Frame,
+	wide_focal_length: f32,
+	tele: Option<&Frame>,
+	focal_length: f32,
+	plane_distance: f32,
+	options: &ZoomOptions,
+) -> FloatImage {
+	let image = &wide.view.image;
+	let (width, height, channels) = (image.width, image.height, image.channels);
+	let crop = wide_focal_length / focal_length;
+	// Zoom in on where the optical axis hits the image, not its middle
+	let (center_x, center_y) = (wide.view.intrinsics[2], wide.view.intrinsics[5]);
+
+	let aligned =
+		tele.and_then(|tele| Aligned::new(wide, tele, plane_distance, options.search_radius));
+	let margin = aligned
+		.map(|a| (a.frame.view.image.width as f32 * options.feather).max(1.0))
+		.unwrap_or(1.0);
+
+	let mut out = FloatImage::new(width, height, channels);
+	for y in 0..height {
+		for x in 0..width {
+			let wx = center_x + (x as f32 - center_x) * crop;
+			let wy = center_y + (y as f32 - center_y) * crop;
+
+			let tele = aligned.and_then(|a| {
+				let (sample, _) = a.sample(wx, wy)?;
+
+				// How far inside of the tele image we are
+				let tele = &a.frame.view.image;
+				let (tx, ty) = project(&a.homography, wx + a.shift.0, wy + a.shift.1);
+				let inside = tx
+					.min(ty)
+					.min(tele.width as f32 - 1.0 - tx)
+					.min(tele.height as f32 - 1.0 - ty);
+
+				let t = (inside / margin).clamp(0.0, 1.0);
+				Some((sample, t * t * (3.0 - 2.0 * t)))
+			});
+
+			for c in 0..channels {
+				let base = image.sample(wx, wy, c).unwrap_or(0.0);
+				let value = match &tele {
+					Some((sample, blend)) => base * (1.0 - blend) + sample[c] * blend,
+					None => base,
+				};
+
+				out.set(x, y, c, value);
+			}
+		}
+	}
+
+	out
+}
@@ -4,13 +4,17 @@ use std::{
 };
 
 use camino::Utf8PathBuf;
-use lri_rs::{AwbMode, DataFormat, HdrMode, LriFile, SceneMode, SensorModel};
+use lri_rs::{
+	AwbMode, BayerJpegHeader, BayerJpegReconstruction, DataFormat, HdrMode, LriFile, RawData,
+	SceneMode, SensorModel,
+};
 use owo_colors::OwoColorize;
 
 fn main() {
-	#[allow(clippy::single_match)]
 	match std::env::args().nth(1).as_deref() {
 		Some("gather") => gather(),
+		Some("bjpg-headers") => bjpg_headers(),
+		Some("bjpg-compare") => bjpg_compare(),
 		_ => (),
 	}
 }
@@ -63,10 +67,20 @@ fn gather() -> ! {
 				continue;
 			}
 		};
-		let lri = LriFile::decode(&data);
+		let (lri, truncation) = match LriFile::recover(&data) {
+			Ok(recovered) => recovered,
+			Err(e) => {
+				println!("{}: {}", lri_path.red(), e);
+				continue;
+			}
+		};
 
 		print!("{} - ", lri_path.file_stem().unwrap());
 
+		if let Some(time) = lri.capture_time() {
+			print!("{} ", time.dimmed());
+		}
+
 		if let Some(fwv) = lri.firmware_version.as_ref() {
 			print!(
 				"[{}] focal:{:<3} iit:{:>2}ms gain:{:2.0} ",
@@ -112,6 +126,7 @@ fn gather() -> ! {
 				None => print!("{}:", "awb".dimmed()),
 				Some(AwbMode::Auto) => print!("{}:", "awb".white()),
 				Some(AwbMode::Daylight) => print!("{}:", "awb".yellow()),
+				Some(_) => print!("{}:", "awb".blue()),
 			}
 
 			match lri.awb_gain {
@@ -140,6 +155,22 @@ fn gather() -> ! {
 			match img.format {
 				DataFormat::BayerJpeg => print!("{} ", sens.cyan()),
 				DataFormat::Packed10bpp => print!("{} ", sens.yellow()),
+				DataFormat::Packed12bpp | DataFormat::Packed14bpp => {
+					print!("{} ", sens.bright_magenta())
+				}
+			}
+		}
+
+		if !truncation.is_complete() {
+			print!(
+				"{} {}b lost:{:?}",
+				"truncated".red(),
+				truncation.missing_bytes,
+				truncation.lost_modules
+			);
+
+			if truncation.lost_message {
+				print!(" {}", "+msg".red());
 			}
 		}
 		println!();
@@ -150,6 +181,106 @@ fn gather() -> ! {
 	std::process::exit(0)
 }
 
+/// Compare the BayerJPEG headers of every LRI in a folder and print which
+/// words of the unknown part of the header change between them.
+fn bjpg_headers() -> ! {
+	let path = std::env::args().nth(2).unwrap();
+	let data_dir = Utf8PathBuf::from(path);
+
+	let mut paths: Vec<Utf8PathBuf> = data_dir
+		.read_dir_utf8()
+		.unwrap()
+		.map(|entry| entry.unwrap().into_path())
+		.filter(|path| path.extension() == Some("lri"))
+		.collect();
+	paths.sort();
+
+	const WORDS: usize = BayerJpegHeader::UNKNOWN_LENGTH / 4;
+	// How many times we've seen each value of every word
+	let mut seen: Vec<HashMap<u32, usize>> = vec![HashMap::new(); WORDS];
+	// If the word was different between images of the same file
+	let mut within_file = [false; WORDS];
+	let mut headers = 0;
+
+	for path in &paths {
+		let data = match std::fs::read(path) {
+			Ok(d) => d,
+			Err(e) => {
+				println!("{}: {}", path.red(), e);
+				continue;
+			}
+		};
+		let (lri, _) = match LriFile::recover(&data) {
+			Ok(recovered) => recovered,
+			Err(e) => {
+				println!("{}: {}", path.red(), e);
+				continue;
+			}
+		};
+
+		let mut first: Option<[u32; WORDS]> = None;
+		let mut count = 0;
+		for img in lri.images() {
+			let RawData::BayerJpeg { header, .. } = &img.data else {
+				continue;
+			};
+
+			let words = header.unknown_u32();
+			for (idx, word) in words.iter().enumerate() {
+				*seen[idx].entry(*word).or_default() += 1;
+
+				if first.is_some_and(|f| f[idx] != *word) {
+					within_file[idx] = true;
+				}
+			}
+
+			first.get_or_insert(words);
+			count += 1;
+		}
+
+		headers += count;
+		if count > 0 {
+			println!("{} - {count} BayerJPEG", path.file_stem().unwrap());
+		}
+	}
+
+	println!(
+		"        ---\n{headers} headers from {} files\n{}",
+		paths.len(),
+		"offset distinct      min        max      min(f32)     max(f32)".dimmed()
+	);
+
+	for (idx, values) in seen.iter().enumerate() {
+		if values.len() < 2 {
+			continue;
+		}
+
+		let min = *values.keys().min().unwrap();
+		let max = *values.keys().max().unwrap();
+		let offset = format!("{:#06x}", 24 + idx * 4);
+
+		println!(
+			"{} {:>8} {min:#010x} {max:#010x} {:>12.5e} {:>12.5e}",
+			if within_file[idx] {
+				offset.yellow().to_string()
+			} else {
+				offset
+			},
+			values.len(),
+			f32::from_bits(min),
+			f32::from_bits(max)
+		);
+	}
+
+	let constant = seen.iter().filter(|values| values.len() == 1).count();
+	println!(
+		"{constant} of {WORDS} words never changed, {} changed between images of the same file",
+		within_file.iter().filter(|w| **w).count().yellow()
+	);
+
+	std::process::exit(0)
+}
+
 struct Photo {
 	jpg: Option<Utf8PathBuf>,
 	lri: Option<Utf8PathBuf>,
@@ -179,3 +310,77 @@ impl Photo {
 		}
 	}
 }
+
+/// Compare the BayerJPEG images of one LRI against the packed images of
+/// another, of the same scene, to see which [BayerJpegReconstruction] gets
+/// back what the sensor puts out. The two shots won't line up pixel for
+/// pixel, so this only compares the mean of each position of the 2x2 cells.
+/// If a reconstruction is right its ratios to the packed means should all be
+/// close to 1.0.
+fn bjpg_compare() -> ! {
+	let bjpg_path = std::env::args().nth(2).unwrap();
+	let packed_path = std::env::args().nth(3).unwrap();
+
+	let bjpg_data = std::fs::read(bjpg_path).unwrap();
+	let packed_data = std::fs::read(packed_path).unwrap();
+	let bjpg = LriFile::decode(&bjpg_data);
+	let packed = LriFile::decode(&packed_data);
+
+	for img in bjpg.images() {
+		let Some(unpacked) = packed
+			.images()
+			.find(|other| other.camera == img.camera)
+			.and_then(|other| other.unpack())
+		else {
+			continue;
+		};
+		let reference = cell_means(&unpacked, img.width, img.height);
+
+		println!(
+			"{} packed    {}",
+			img.camera,
+			reference.map(|m| format!("{m:8.1}")).join(" ")
+		);
+
+		for mode in [
+			BayerJpegReconstruction::PerChannel,
+			BayerJpegReconstruction::Summed,
+		] {
+			let reconstructed = match img.reconstruct_bayer_jpeg(mode) {
+				Ok(Some(rec)) => rec,
+				Ok(None) => break,
+				Err(e) => {
+					println!("{}", e.red());
+					break;
+				}
+			};
+
+			let means = cell_means(&reconstructed, img.width, img.height);
+			let ratios = std::array::from_fn::<_, 4, _>(|idx| means[idx] / reference[idx]);
+			println!(
+				"   {:<10} {} {}",
+				format!("{mode:?}"),
+				means.map(|m| format!("{m:8.1}")).join(" "),
+				ratios.map(|r| format!("{r:.3}")).join(" ").dimmed()
+			);
+		}
+	}
+
+	std::process::exit(0)
+}
+
+/// The mean of each position of the 2x2 cells, left to right and top to bottom
+fn cell_means(mosaic: &[u16], width: usize, height: usize) -> [f32; 4] {
+	let mut sums = [0.0; 4];
+	let mut counts = [0; 4];
+
+	for (idx, px) in mosaic.iter().take(width * height).enumerate() {
+		let (x, y) = (idx % width, idx / width);
+		let position = (y % 2) * 2 + x % 2;
+
+		sums[position] += *px as f64;
+		counts[position] += 1;
+	}
+
+	std::array::from_fn(|idx| (sums[idx] / counts[idx].max(1) as f64) as f32)
+}
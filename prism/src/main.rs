@@ -1,12 +1,11 @@
 use std::collections::HashMap;
 
 use camino::Utf8PathBuf;
-use lri_rs::{AwbGain, CameraId, LriFile, RawData, RawImage, SensorModel, Whitepoint};
+use lri_rs::{
+	AwbGain, CameraId, LriFile, ModuleCalibration, MosaicOptions, RawImage, SensorModel, Whitepoint,
+};
 use nalgebra::{Matrix3, Matrix3x1};
 
-mod rotate;
-mod unpack;
-
 pub struct Entry {
 	sensor: SensorModel,
 	count: usize,
@@ -34,7 +33,12 @@ fn main() {
 	println!("{} images", lri.image_count());
 
 	if let Some(refimg) = lri.reference_image() {
-		make(refimg, directory.join("reference.png"), gain);
+		make(
+			refimg,
+			lri.calibration(refimg.camera),
+			directory.join("reference.png"),
+			gain,
+		);
 	}
 
 	let mut set: HashMap<CameraId, Entry> = HashMap::new();
@@ -53,11 +57,21 @@ fn main() {
 	});*/
 
 	for (idx, img) in lri.images().enumerate() {
-		make(img, directory.join(format!("image_{idx}.png")), gain);
+		make(
+			img,
+			lri.calibration(img.camera),
+			directory.join(format!("image_{idx}.png")),
+			gain,
+		);
 	}
 }
 
-fn make(img: &RawImage, path: Utf8PathBuf, awb_gain: AwbGain) {
+fn make(
+	img: &RawImage,
+	calibration: Option<&ModuleCalibration>,
+	path: Utf8PathBuf,
+	awb_gain: AwbGain,
+) {
 	use rawproc::image::RawMetadata;
 	use rawproc::{colorspace::BayerRgb, image::Image};
 
@@ -67,9 +81,9 @@ fn make(img: &RawImage, path: Utf8PathBuf, awb_gain: AwbGain) {
 		width,
 		height,
 		format,
-		data,
 		sbro,
 		color,
+		..
 	} = img;
 
 	println!(
@@ -77,9 +91,10 @@ fn make(img: &RawImage, path: Utf8PathBuf, awb_gain: AwbGain) {
 		sbro.0, sbro.1
 	);
 
-	let bayered = bayer(data, *width, *height);
+	let mut bayered = bayer(img, calibration);
+	img.make_upright(&mut bayered);
 
-	let (mut rgb, color_format) = match img.cfa_string() {
+	let (rgb, color_format) = match img.upright_cfa_string() {
 		Some(cfa_string) => {
 			let rawimg: Image<u8, BayerRgb> = Image::from_raw_parts(
 				4160,
@@ -87,7 +102,8 @@ fn make(img: &RawImage, path: Utf8PathBuf, awb_gain: AwbGain) {
 				// We only care about CFA here because all we're doing is debayering
 				RawMetadata {
 					whitebalance: [1.0; 3],
-					whitelevels: [1024; 3],
+					// bayer() already mapped the white level to the top of a u8
+					whitelevels: [u8::MAX as u16; 3],
 					crop: None,
 					// ugh CFA isn't exposed, so we pulled in rawloader for now
 					cfa: rawloader::CFA::new(cfa_string),
@@ -102,7 +118,6 @@ fn make(img: &RawImage, path: Utf8PathBuf, awb_gain: AwbGain) {
 		None => (bayered, png::ColorType::Grayscale),
 	};
 
-	rotate::rotate_180(rgb.as_mut_slice());
 	let mut floats: Vec<f32> = rgb.into_iter().map(|p| p as f32 / 255.0).collect();
 
 	if !color.is_empty() {
@@ -183,74 +198,32 @@ pub fn srgb_gamma(mut float: f32) -> f32 {
 	float.clamp(0.0, 1.0)
 }
 
-fn bayer(data: &RawData<'_>, width: usize, height: usize) -> Vec<u8> {
-	match data {
-		RawData::Packed10bpp { data } => {
-			let size = width * height;
-			let mut ten_data = vec![0; size];
-			unpack::tenbit(data, width * height, ten_data.as_mut_slice());
-
-			// I've only seen it on one color defintion or
-			// something, but there's a black level of 42, so subtract it.
-			// without it the image is entirely too red.
-			//ten_data.iter_mut().for_each(|p| *p = p.saturating_sub(42));
-
-			ten_data
-				.into_iter()
-				.map(|p| ((p.saturating_sub(42)) >> 2) as u8)
-				.collect()
-		}
-		RawData::BayerJpeg {
-			header: _,
-			format,
-			jpeg0,
-			jpeg1,
-			jpeg2,
-			jpeg3,
-		} => {
-			let mut bayered = vec![0; width * height];
-
-			match format {
-				0 => {
-					let mut into = vec![0; (width * height) / 4];
-
-					let mut channel = |jpeg: &[u8], offset: usize| {
-						zune_jpeg::JpegDecoder::new(jpeg)
-							.decode_into(&mut into)
-							.unwrap();
-
-						for idx in 0..into.len() {
-							let ww = width / 2;
-							let in_x = idx % ww;
-							let in_y = idx / ww;
-
-							let bayer_x = (in_x * 2) + (offset % 2);
-							let bayer_y = (in_y * 2) + (offset / 2);
-
-							let bayer_idx = bayer_y * width + bayer_x;
-							bayered[bayer_idx] = into[idx];
-						}
-					};
-
-					//BGGR
-					//RGGB
-					//GRBG
-					channel(jpeg0, 0);
-					channel(jpeg1, 1);
-					channel(jpeg2, 2);
-					channel(jpeg3, 3);
-				}
-				1 => {
-					zune_jpeg::JpegDecoder::new(jpeg0)
-						.decode_into(&mut bayered)
-						.unwrap();
-				}
-				_ => unreachable!(),
-			}
+fn bayer(img: &RawImage<'_>, calibration: Option<&ModuleCalibration>) -> Vec<u8> {
+	if let Some(mosaic) = img.linear_mosaic(calibration, &MosaicOptions::default()) {
+		return mosaic
+			.into_iter()
+			.map(|p| (p.clamp(0.0, 1.0) * 255.0) as u8)
+			.collect();
+	}
 
-			bayered
-		}
+	if let Some(unpacked) = img.unpack() {
+		let bits = img.format.bits_per_pixel().unwrap();
+
+		// Older files might not have the sensor characterization. Before we read
+		// it we used a black level of 42, which got rid of the image being entirely
+		// too red, and the full range of the data.
+		let black = (42 << (bits - 10)) as f32;
+		let white = (1 << bits) as f32;
+		let range = white - black;
+
+		return unpacked
+			.into_iter()
+			.map(|p| (((p as f32 - black) / range).clamp(0.0, 1.0) * 255.0) as u8)
+			.collect();
 	}
+
+	// Packed formats were handled above, so this is a BayerJPEG
+	img.decode_bayer_jpeg().unwrap().unwrap()
 }
 
 fn make_png<P: AsRef<std::path::Path>>(